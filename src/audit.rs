@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::crypto::CryptoManager;
+
+/// Binds every line to the audit log so it cannot be passed off as a value.
+const AUDIT_AAD: &[u8] = b"srs audit";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Add,
+    Update,
+    Get,
+    Delete,
+    Rename,
+    Shell,
+    Run,
+    AddAlias,
+    RemoveAlias,
+}
+
+impl std::fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            AuditAction::Add => "add",
+            AuditAction::Update => "update",
+            AuditAction::Get => "get",
+            AuditAction::Delete => "delete",
+            AuditAction::Rename => "rename",
+            AuditAction::Shell => "shell",
+            AuditAction::Run => "run",
+            AuditAction::AddAlias => "add-alias",
+            AuditAction::RemoveAlias => "remove-alias",
+        })
+    }
+}
+
+/// One access to the store. Only token names are recorded, never values.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AuditEvent {
+    pub at: u64,
+    pub action: AuditAction,
+    /// The tokens involved: `[old, new]` for a rename, `[alias, target]` for
+    /// alias changes, every exported variable for `shell` and `run`.
+    pub names: Vec<String>,
+}
+
+/// The audit log kept next to a store, e.g. `srs.audit` for `srs.json`.
+pub fn audit_path(store: &Path) -> PathBuf {
+    store.with_extension("audit")
+}
+
+/// Appends `event` as one encrypted line, so the log reveals no more than
+/// the store itself: how many events there are, not what they were.
+pub(crate) fn append(path: &Path, crypto: &CryptoManager, event: &AuditEvent) -> Result<()> {
+    let line = crypto.encrypt_with_aad(&serde_json::to_vec(event)?, AUDIT_AAD)?;
+    let mut file = open_options()
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Could not write the audit log '{}': {e}", path.display()))?;
+    writeln!(file, "{line}")?;
+    Ok(())
+}
+
+/// Reads every event, oldest first. A missing log has no events.
+pub(crate) fn read(path: &Path, crypto: &CryptoManager) -> Result<Vec<AuditEvent>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(anyhow!(
+                "Could not read the audit log '{}': {e}",
+                path.display()
+            ))
+        }
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            crypto
+                .decrypt_with_aad(line.trim(), AUDIT_AAD)
+                .ok()
+                .and_then(|plaintext| serde_json::from_slice(&plaintext).ok())
+                .ok_or_else(|| anyhow!("Audit log line {} could not be decrypted", index + 1))
+        })
+        .collect()
+}
+
+/// Replaces the log with `events`, e.g. re-encrypted under a new master key.
+pub(crate) fn rewrite(path: &Path, crypto: &CryptoManager, events: &[AuditEvent]) -> Result<()> {
+    let mut content = String::new();
+    for event in events {
+        content.push_str(&crypto.encrypt_with_aad(&serde_json::to_vec(event)?, AUDIT_AAD)?);
+        content.push('\n');
+    }
+    let mut file = open_options().truncate(true).open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn open_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(action: AuditAction, names: &[&str]) -> AuditEvent {
+        AuditEvent {
+            at: 1_700_000_000,
+            action,
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn append_and_read_round_trip() {
+        let path = std::env::temp_dir().join(format!("srs_audit_{}.audit", uuid::Uuid::new_v4()));
+        let crypto = CryptoManager::from_key([7; 32]);
+        assert!(read(&path, &crypto).unwrap().is_empty());
+
+        append(&path, &crypto, &event(AuditAction::Add, &["github"])).unwrap();
+        append(&path, &crypto, &event(AuditAction::Get, &["github"])).unwrap();
+        assert_eq!(
+            read(&path, &crypto).unwrap(),
+            [
+                event(AuditAction::Add, &["github"]),
+                event(AuditAction::Get, &["github"])
+            ]
+        );
+        assert!(!fs::read_to_string(&path).unwrap().contains("github"));
+
+        let other = CryptoManager::from_key([8; 32]);
+        let error = read(&path, &other).unwrap_err();
+        assert_eq!(error.to_string(), "Audit log line 1 could not be decrypted");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn audit_path_sits_next_to_the_store() {
+        assert_eq!(
+            audit_path(Path::new("/data/srs/work.json")),
+            PathBuf::from("/data/srs/work.audit")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use arboard::Clipboard;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+pub const HOLD_COMMAND: &str = "clipboard-hold";
+
+pub fn read_text(keep_whitespace: bool) -> Result<String> {
+    let mut clipboard =
+        Clipboard::new().map_err(|e| anyhow!("Could not access the clipboard: {e}"))?;
+    let text = clipboard
+        .get_text()
+        .map_err(|e| anyhow!("Clipboard does not contain any text: {e}"))?;
+    normalize(text, keep_whitespace)
+}
+
+/// Copies `text` to the clipboard from a detached helper process that restores
+/// the previous contents after `clear_after` seconds. The helper outlives this
+/// process so the value survives on platforms where the clipboard owner has
+/// to keep running (X11).
+pub fn copy_with_timeout(text: &str, clear_after: u64) -> Result<()> {
+    Clipboard::new().map_err(|e| anyhow!("Could not access the clipboard: {e}"))?;
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .args([HOLD_COMMAND, "--clear-after", &clear_after.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Could not pass the value to the clipboard helper"))?
+        .write_all(text.as_bytes())?;
+    Ok(())
+}
+
+/// Entry point of the helper spawned by `copy_with_timeout`, reading the value
+/// from stdin.
+pub fn hold_from_stdin(clear_after: u64) -> Result<()> {
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text)?;
+
+    let mut clipboard =
+        Clipboard::new().map_err(|e| anyhow!("Could not access the clipboard: {e}"))?;
+    let previous = clipboard.get_text().ok();
+    clipboard.set_text(text.as_str())?;
+
+    thread::sleep(Duration::from_secs(clear_after));
+
+    // Leave the clipboard alone if the user has copied something else since.
+    if clipboard.get_text().ok().as_deref() == Some(text.as_str()) {
+        match previous {
+            Some(previous) => clipboard.set_text(previous)?,
+            None => clipboard.clear()?,
+        }
+    }
+    Ok(())
+}
+
+fn normalize(text: String, keep_whitespace: bool) -> Result<String> {
+    let value = if keep_whitespace {
+        text
+    } else {
+        text.trim_end().to_string()
+    };
+
+    if value.is_empty() {
+        return Err(anyhow!("Clipboard is empty, nothing to store."));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_trims_trailing_whitespace() {
+        let value = normalize("ghp_secret\n  ".to_string(), false).unwrap();
+        assert_eq!(value, "ghp_secret");
+    }
+
+    #[test]
+    fn normalize_keeps_whitespace_when_asked() {
+        let value = normalize("ghp_secret\n".to_string(), true).unwrap();
+        assert_eq!(value, "ghp_secret\n");
+    }
+
+    #[test]
+    fn normalize_rejects_empty_clipboard() {
+        assert!(normalize(String::new(), false).is_err());
+        assert!(normalize(" \n\t".to_string(), false).is_err());
+    }
+}
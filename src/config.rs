@@ -0,0 +1,116 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::storage::DATA_DIR;
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Settings read from `config.toml` in the srs config directory. Every field
+/// is optional; a missing file behaves like an empty one.
+///
+/// ```toml
+/// data_dir = "~/Sync/srs"
+/// profile = "work"
+/// audit = true
+/// ```
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory holding the store files instead of the platform data dir.
+    pub data_dir: Option<PathBuf>,
+    /// Profile used when `--profile` is not given.
+    pub profile: Option<String>,
+    /// Records reads and changes in an encrypted log next to the store.
+    pub audit: bool,
+}
+
+/// Where the config file is looked up, e.g. `~/.config/srs/config.toml`.
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("srs").join(CONFIG_FILE))
+}
+
+impl Config {
+    /// Reads the config file, falling back to the defaults when there is none.
+    pub fn load() -> Result<Self> {
+        match config_path() {
+            Some(path) => Self::from_path(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)
+                .map_err(|e| anyhow::anyhow!("Invalid config file '{}': {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow::anyhow!(
+                "Could not read config file '{}': {e}",
+                path.display()
+            )),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// The directory holding the stores, with a leading `~` expanded.
+    pub fn data_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(dir) => match (dir.strip_prefix("~"), dirs::home_dir()) {
+                (Ok(rest), Some(home)) => home.join(rest),
+                _ => dir.clone(),
+            },
+            None => DATA_DIR.clone(),
+        }
+    }
+
+    /// The profile to open: the one asked for, else the configured default.
+    pub fn profile<'a>(&'a self, requested: Option<&'a str>) -> Option<&'a str> {
+        requested.or(self.profile.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_known_keys() {
+        let config =
+            Config::parse("data_dir = \"/tmp/srs\"\nprofile = \"work\"\naudit = true\n").unwrap();
+        assert_eq!(config.data_dir(), PathBuf::from("/tmp/srs"));
+        assert!(config.audit);
+        assert_eq!(config.profile(None), Some("work"));
+        assert_eq!(config.profile(Some("personal")), Some("personal"));
+    }
+
+    #[test]
+    fn parse_rejects_unknown_keys() {
+        assert!(Config::parse("service = \"srs\"\n").is_err());
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.data_dir(), *DATA_DIR);
+        assert_eq!(config.profile(None), None);
+    }
+
+    #[test]
+    fn missing_file_uses_defaults() {
+        let path = std::env::temp_dir().join(format!("srs_config_{}", uuid::Uuid::new_v4()));
+        assert_eq!(Config::from_path(&path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn data_dir_expands_home() {
+        let config = Config::parse("data_dir = \"~/srs-stores\"\n").unwrap();
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(config.data_dir(), home.join("srs-stores"));
+        }
+    }
+}
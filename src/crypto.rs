@@ -1,29 +1,250 @@
-use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{engine::general_purpose, Engine as _};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
 use rand::Rng;
 use rpassword::read_password;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Domain separation for the MAC key, so it never equals the encryption key.
+const MAC_KEY_LABEL: &[u8] = b"srs store mac";
+/// Domain separation for [`CryptoManager::fingerprint`].
+const FINGERPRINT_LABEL: &[u8] = b"srs key fingerprint";
+
+/// Compares secrets such as MAC tags and key-check markers. `==` stops at
+/// the first differing byte, so how long a failed check takes would tell an
+/// attacker how much of a forged tag was right; this always looks at every
+/// byte. Slices of different lengths are unequal.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+const MASTER_KEY_PROMPT: &str = "Please enter your master key: ";
+pub const MASTER_KEY_ENV: &str = "SRS_MASTER_KEY";
+pub const ASKPASS_ENV: &str = "SRS_ASKPASS";
+
+pub enum KeySource {
+    Prompt,
+    Askpass(String),
+    /// The key itself, already read from stdin or `SRS_MASTER_KEY`.
+    Provided(String),
+}
+
+impl KeySource {
+    /// Picks where the master key comes from: the command line first, then
+    /// the environment, then the interactive prompt. `SRS_MASTER_KEY` is
+    /// removed from the environment once read so spawned shells don't inherit it.
+    pub fn resolve(askpass: Option<String>, master_key_stdin: bool) -> Result<Self> {
+        let env_key = std::env::var_os(MASTER_KEY_ENV);
+        std::env::remove_var(MASTER_KEY_ENV);
+
+        if master_key_stdin {
+            crate::debug!("::> Reading the master key from stdin.");
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            let key = line.trim_end_matches(['\n', '\r']);
+            return Ok(KeySource::Provided(key.to_string()));
+        }
+        if let Some(command) = askpass.filter(|command| !command.is_empty()) {
+            crate::debug!("::> Reading the master key from --askpass '{command}'.");
+            return Ok(KeySource::Askpass(command));
+        }
+        if let Some(key) = env_key {
+            crate::debug!("::> Reading the master key from {MASTER_KEY_ENV}.");
+            let key = key
+                .into_string()
+                .map_err(|_| anyhow!("{MASTER_KEY_ENV} is not valid UTF-8"))?;
+            return Ok(KeySource::Provided(key));
+        }
+        match std::env::var(ASKPASS_ENV) {
+            Ok(command) if !command.is_empty() => {
+                crate::debug!("::> Reading the master key from {ASKPASS_ENV} '{command}'.");
+                Ok(KeySource::Askpass(command))
+            }
+            _ => {
+                crate::debug!("::> Prompting for the master key.");
+                Ok(KeySource::Prompt)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// PBKDF2 rounds for new stores, OWASP's recommendation for HMAC-SHA256.
+pub const PBKDF2_DEFAULT_ITERATIONS: u32 = 600_000;
+
+/// The key derivation functions a store can be created with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum KdfAlgorithm {
+    Argon2id,
+    /// PBKDF2-HMAC-SHA256, for machines that cannot spare Argon2's memory.
+    Pbkdf2,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum Kdf {
+    #[default]
+    LegacySha256,
+    Argon2id {
+        salt: String,
+        params: Argon2Params,
+    },
+    Pbkdf2Sha256 {
+        salt: String,
+        iterations: u32,
+    },
+}
+
+impl std::fmt::Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kdf::LegacySha256 => write!(f, "SHA-256 (legacy)"),
+            Kdf::Argon2id { params, .. } => write!(
+                f,
+                "Argon2id, {} KiB memory, {} iteration(s), parallelism {}",
+                params.memory_kib, params.iterations, params.parallelism
+            ),
+            Kdf::Pbkdf2Sha256 { iterations, .. } => {
+                write!(f, "PBKDF2-HMAC-SHA256, {iterations} iteration(s)")
+            }
+        }
+    }
+}
+
+impl Kdf {
+    pub fn argon2id() -> Self {
+        Kdf::Argon2id {
+            salt: general_purpose::STANDARD.encode(random_salt()),
+            params: Argon2Params::default(),
+        }
+    }
+
+    pub fn pbkdf2(iterations: u32) -> Self {
+        Kdf::Pbkdf2Sha256 {
+            salt: general_purpose::STANDARD.encode(random_salt()),
+            iterations,
+        }
+    }
+
+    /// A new `algorithm` KDF with a fresh salt. `iterations` is only used by
+    /// PBKDF2; Argon2id keeps its default cost parameters.
+    pub fn with_algorithm(algorithm: KdfAlgorithm, iterations: u32) -> Self {
+        match algorithm {
+            KdfAlgorithm::Argon2id => Self::argon2id(),
+            KdfAlgorithm::Pbkdf2 => Self::pbkdf2(iterations),
+        }
+    }
+
+    /// The algorithm, or `None` for the legacy unsalted SHA-256 key.
+    pub fn algorithm(&self) -> Option<KdfAlgorithm> {
+        match self {
+            Kdf::LegacySha256 => None,
+            Kdf::Argon2id { .. } => Some(KdfAlgorithm::Argon2id),
+            Kdf::Pbkdf2Sha256 { .. } => Some(KdfAlgorithm::Pbkdf2),
+        }
+    }
+
+    /// The same algorithm and cost with a fresh salt, for re-keying. The
+    /// legacy SHA-256 derivation is replaced by Argon2id.
+    pub fn renewed(&self) -> Self {
+        match self {
+            Kdf::LegacySha256 => Self::argon2id(),
+            Kdf::Argon2id { params, .. } => Kdf::Argon2id {
+                salt: general_purpose::STANDARD.encode(random_salt()),
+                params: *params,
+            },
+            Kdf::Pbkdf2Sha256 { iterations, .. } => Self::pbkdf2(*iterations),
+        }
+    }
+
+    pub fn derive(&self, passphrase: &str) -> Result<[u8; 32]> {
+        match self {
+            Kdf::LegacySha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(passphrase.as_bytes());
+                let hash = hasher.finalize();
+
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&hash);
+                Ok(key)
+            }
+            Kdf::Argon2id { salt, params } => {
+                derive_argon2_key(passphrase, &decode_salt(salt)?, params)
+            }
+            Kdf::Pbkdf2Sha256 { salt, iterations } => {
+                derive_pbkdf2_key(passphrase, &decode_salt(salt)?, *iterations)
+            }
+        }
+    }
+}
+
+fn decode_salt(salt: &str) -> Result<Vec<u8>> {
+    general_purpose::STANDARD
+        .decode(salt)
+        .ok()
+        .filter(|salt| salt.len() >= argon2::MIN_SALT_LEN)
+        .ok_or_else(|| anyhow!("Key derivation salt is corrupt, the store cannot be opened."))
+}
+
+/// AES-256-GCM with a 32-byte master key. Ciphertexts are base64 of the
+/// random 12-byte nonce followed by the sealed data.
 pub struct CryptoManager {
     master_key: [u8; 32],
 }
 
 impl CryptoManager {
-    pub fn new() -> Result<Self> {
-        Ok(Self {
-            master_key: derive_master_key().expect("Could not derive master key."),
-        })
+    /// Derives the master key from `passphrase` with `kdf`.
+    pub fn new(passphrase: &str, kdf: &Kdf) -> Result<Self> {
+        let mut key = kdf.derive(passphrase)?;
+        let crypto_manager = Self::from_key(key);
+        key.zeroize();
+        Ok(crypto_manager)
     }
 
-    #[cfg(test)]
+    /// Uses `key` as the master key directly.
     pub fn from_key(key: [u8; 32]) -> Self {
         Self { master_key: key }
     }
 
+    /// Encrypts under a fresh random nonce.
     pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        self.encrypt_bytes(plaintext.as_bytes())
+    }
+
+    pub fn encrypt_bytes(&self, plaintext: &[u8]) -> Result<String> {
+        self.encrypt_with_aad(plaintext, &[])
+    }
+
+    /// Encrypts with `aad` as associated data: it is not stored, but the same
+    /// bytes must be passed to [`Self::decrypt_with_aad`] or decryption fails.
+    pub fn encrypt_with_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<String> {
         let mut nonce_bytes = [0u8; 12];
         rand::thread_rng().fill(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
@@ -32,7 +253,13 @@ impl CryptoManager {
         let cipher = Aes256Gcm::new(key);
 
         let ciphertext = cipher
-            .encrypt(nonce, plaintext.as_bytes())
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
             .map_err(|e| anyhow!("Encryption failed: {e}"))?;
 
         let mut encrypted_data = nonce_bytes.to_vec();
@@ -42,47 +269,220 @@ impl CryptoManager {
     }
 
     pub fn decrypt(&self, encrypted_data: &str) -> Result<String> {
-        let encrypted_bytes = general_purpose::STANDARD
-            .decode(encrypted_data)
-            .map_err(|e| anyhow!("Store possibly corrupt, please recreate your store: {e}"))?;
+        utf8_text(self.decrypt_bytes(encrypted_data)?)
+    }
 
-        if encrypted_bytes.len() < 12 {
-            return Err(anyhow!("Invalid encrypted data found."));
-        }
+    pub fn decrypt_lossy(&self, encrypted_data: &str) -> Result<String> {
+        let plaintext = Zeroizing::new(self.decrypt_bytes(encrypted_data)?);
+        Ok(String::from_utf8_lossy(&plaintext).into_owned())
+    }
+
+    pub fn decrypt_bytes(&self, encrypted_data: &str) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(encrypted_data, &[])
+    }
 
+    pub fn decrypt_with_aad(&self, encrypted_data: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let encrypted_bytes = decode_encrypted(encrypted_data)?;
         let (nonce_bytes, ciphertext) = encrypted_bytes.split_at(12);
         let nonce = Nonce::from_slice(nonce_bytes);
 
         let key = Key::<Aes256Gcm>::from_slice(&self.master_key);
         let cipher = Aes256Gcm::new(key);
 
-        let plaintext = cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| anyhow!("Error occurred during decryption: {e}"))?;
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|e| anyhow!("Error occurred during decryption: {e}"))
+    }
+
+    /// HMAC-SHA256 of `data` under a key derived from the master key, kept
+    /// separate from the encryption key. Returned as base64.
+    pub fn mac(&self, data: &[u8]) -> String {
+        let mut mac = self.mac_state();
+        mac.update(data);
+        general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Checks a tag from [`Self::mac`] in constant time, through
+    /// [`constant_time_eq`].
+    pub fn verify_mac(&self, data: &[u8], tag: &str) -> bool {
+        let Ok(tag) = general_purpose::STANDARD.decode(tag) else {
+            return false;
+        };
+        let mut mac = self.mac_state();
+        mac.update(data);
+        constant_time_eq(&mac.finalize().into_bytes(), &tag)
+    }
+
+    /// A short, non-reversible fingerprint of the derived key, such as
+    /// `3f9a-07c2-d41e-b860`. Equal fingerprints mean equal keys, while the
+    /// fingerprint itself reveals nothing about the key or any value.
+    pub fn fingerprint(&self) -> String {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.master_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(FINGERPRINT_LABEL);
+        let digest = mac.finalize().into_bytes();
+        digest[..8]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    fn mac_state(&self) -> HmacSha256 {
+        let mut derive = <HmacSha256 as Mac>::new_from_slice(&self.master_key)
+            .expect("HMAC accepts keys of any length");
+        derive.update(MAC_KEY_LABEL);
+        let mut key: [u8; 32] = derive.finalize().into_bytes().into();
+        let mac =
+            <HmacSha256 as Mac>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+        key.zeroize();
+        mac
+    }
+}
+
+impl Zeroize for CryptoManager {
+    fn zeroize(&mut self) {
+        self.master_key.zeroize();
+    }
+}
+
+impl Drop for CryptoManager {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// Converts a decrypted value to text, pointing at `--lossy` when it is not
+/// valid UTF-8.
+pub fn utf8_text(plaintext: Vec<u8>) -> Result<String> {
+    String::from_utf8(plaintext).map_err(|e| {
+        let message = e.utf8_error().to_string();
+        e.into_bytes().zeroize();
+        anyhow!("Error occurred during reconstruction, use --lossy to inspect the value: {message}")
+    })
+}
+
+fn decode_encrypted(encrypted_data: &str) -> Result<Vec<u8>> {
+    let encrypted_bytes = general_purpose::STANDARD
+        .decode(encrypted_data)
+        .map_err(|e| anyhow!("Store possibly corrupt, please recreate your store: {e}"))?;
 
-        String::from_utf8(plaintext)
-            .map_err(|e| anyhow!("Error occurred during reconstruction: {e}"))
+    if encrypted_bytes.len() < 12 {
+        return Err(anyhow!("Invalid encrypted data found."));
     }
+    Ok(encrypted_bytes)
 }
 
-fn derive_master_key() -> Result<[u8; 32]> {
-    print!("Please enter your master key: ");
-    io::stdout().flush().expect("Failed to flush stdout");
-    let input = read_password().expect("Failed to read master key");
+pub fn extract_nonce(encrypted_data: &str) -> Result<[u8; 12]> {
+    let encrypted_bytes = decode_encrypted(encrypted_data)?;
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&encrypted_bytes[..12]);
+    Ok(nonce)
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
-    let hash = hasher.finalize();
+pub fn derive_argon2_key(passphrase: &str, salt: &[u8], params: &Argon2Params) -> Result<[u8; 32]> {
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(32),
+    )
+    .map_err(|e| anyhow!("Invalid key derivation parameters: {e}"))?;
 
     let mut key = [0u8; 32];
-    key.copy_from_slice(&hash);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {e}"))?;
     Ok(key)
 }
 
+pub fn derive_pbkdf2_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32]> {
+    if iterations == 0 {
+        return Err(anyhow!(
+            "Invalid key derivation parameters: PBKDF2 needs at least one iteration"
+        ));
+    }
+
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    Ok(key)
+}
+
+pub fn random_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill(&mut salt);
+    salt
+}
+
+pub fn read_master_key(source: &KeySource) -> Result<String> {
+    match source {
+        KeySource::Prompt => {
+            eprint!("{MASTER_KEY_PROMPT}");
+            io::stderr().flush().expect("Failed to flush stderr");
+            Ok(read_password().expect("Failed to read master key"))
+        }
+        KeySource::Askpass(command) => run_askpass(command),
+        KeySource::Provided(key) if key.is_empty() => {
+            Err(anyhow!("The provided master key is empty."))
+        }
+        KeySource::Provided(key) => Ok(key.clone()),
+    }
+}
+
+fn run_askpass(command: &str) -> Result<String> {
+    let output = Command::new(command)
+        .arg(MASTER_KEY_PROMPT)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("Could not run askpass command '{command}': {e}"))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Askpass command '{command}' failed with {}",
+            output.status
+        ));
+    }
+
+    let input = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("Askpass command '{command}' returned invalid UTF-8: {e}"))?;
+    let input = input.trim_end_matches(['\n', '\r']);
+    if input.is_empty() {
+        return Err(anyhow!(
+            "Askpass command '{command}' returned an empty master key"
+        ));
+    }
+    Ok(input.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn zeroize_clears_the_master_key() {
+        let mut crypto = CryptoManager::new("passphrase", &Kdf::LegacySha256).unwrap();
+        assert_ne!(crypto.master_key, [0u8; 32]);
+
+        crypto.zeroize();
+        assert_eq!(crypto.master_key, [0u8; 32]);
+    }
+
+    #[test]
+    fn drop_zeroizes_the_master_key() {
+        let mut crypto = std::mem::ManuallyDrop::new(CryptoManager::from_key([7u8; 32]));
+        // Run the destructor in place, then inspect the memory it leaves
+        // behind; a `[u8; 32]` has no drop glue of its own, so it stays valid.
+        unsafe { std::ptr::drop_in_place(&mut *crypto) };
+        assert_eq!(crypto.master_key, [0u8; 32]);
+    }
+
     #[test]
     fn encrypt_decrypt() {
         let crypto = CryptoManager::from_key([0u8; 32]);
@@ -100,6 +500,18 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn decrypt_invalid_utf8_is_strict_by_default() {
+        let crypto = CryptoManager::from_key([0u8; 32]);
+        let encrypted = crypto.encrypt_bytes(b"token\xffvalue").unwrap();
+
+        assert!(crypto.decrypt(&encrypted).is_err());
+        assert_eq!(
+            crypto.decrypt_lossy(&encrypted).unwrap(),
+            "token\u{FFFD}value"
+        );
+    }
+
     #[test]
     fn decrypt_corrupted_data() {
         let crypto = CryptoManager::from_key([0u8; 32]);
@@ -115,4 +527,213 @@ mod tests {
         let result = crypto.decrypt(&encrypted);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn extract_nonce_reads_prefix() {
+        let crypto = CryptoManager::from_key([0u8; 32]);
+        let encrypted = crypto.encrypt("secret").unwrap();
+        let decoded = general_purpose::STANDARD.decode(&encrypted).unwrap();
+
+        assert_eq!(extract_nonce(&encrypted).unwrap(), decoded[..12]);
+        assert!(extract_nonce("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn argon2_derivation_is_deterministic() {
+        let params = Argon2Params::default();
+        let salt = [7u8; 16];
+
+        let first = derive_argon2_key("passphrase", &salt, &params).unwrap();
+        let second = derive_argon2_key("passphrase", &salt, &params).unwrap();
+        assert_eq!(first, second);
+
+        let other_salt = derive_argon2_key("passphrase", &[8u8; 16], &params).unwrap();
+        assert_ne!(first, other_salt);
+    }
+
+    #[test]
+    fn kdf_argon2id_is_deterministic_for_stored_salt() {
+        let kdf = Kdf::argon2id();
+        let stored: Kdf = serde_json::from_str(&serde_json::to_string(&kdf).unwrap()).unwrap();
+
+        let first = CryptoManager::new("passphrase", &kdf).unwrap();
+        let second = CryptoManager::new("passphrase", &stored).unwrap();
+        let encrypted = first.encrypt("secret").unwrap();
+        assert_eq!(second.decrypt(&encrypted).unwrap(), "secret");
+
+        let other_salt = CryptoManager::new("passphrase", &Kdf::argon2id()).unwrap();
+        assert!(other_salt.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn mac_verifies_only_the_same_data_and_key() {
+        let crypto = CryptoManager::from_key([1u8; 32]);
+        let tag = crypto.mac(b"store contents");
+
+        assert!(crypto.verify_mac(b"store contents", &tag));
+        assert!(!crypto.verify_mac(b"store content", &tag));
+        assert!(!crypto.verify_mac(b"store contents", "not base64!"));
+        assert!(!CryptoManager::from_key([2u8; 32]).verify_mac(b"store contents", &tag));
+    }
+
+    #[test]
+    fn fingerprint_is_stable_and_differs_between_keys() {
+        let fingerprint = CryptoManager::from_key([1u8; 32]).fingerprint();
+        assert_eq!(fingerprint.len(), 19);
+        assert!(fingerprint
+            .split('-')
+            .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit())));
+        assert_eq!(
+            CryptoManager::from_key([1u8; 32]).fingerprint(),
+            fingerprint
+        );
+        assert_ne!(
+            CryptoManager::from_key([2u8; 32]).fingerprint(),
+            fingerprint
+        );
+
+        // The same passphrase and salt derive the same key on any machine.
+        let kdf = Kdf::pbkdf2(1_000);
+        let first = CryptoManager::new("passphrase", &kdf).unwrap();
+        let second = CryptoManager::new("passphrase", &kdf).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        let other = CryptoManager::new("other", &kdf).unwrap();
+        assert_ne!(first.fingerprint(), other.fingerprint());
+    }
+
+    #[test]
+    fn constant_time_eq_matches_only_identical_bytes() {
+        assert!(constant_time_eq(b"srs-key-check", b"srs-key-check"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"srs-key-check", b"srs-key-chekc"));
+        assert!(!constant_time_eq(b"srs-key-check", b"srs-key-chec"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+
+    #[test]
+    fn pbkdf2_derivation_is_deterministic() {
+        let salt = [7u8; 16];
+
+        let first = derive_pbkdf2_key("passphrase", &salt, 1_000).unwrap();
+        let second = derive_pbkdf2_key("passphrase", &salt, 1_000).unwrap();
+        assert_eq!(first, second);
+
+        assert_ne!(
+            first,
+            derive_pbkdf2_key("passphrase", &[8u8; 16], 1_000).unwrap()
+        );
+        assert_ne!(
+            first,
+            derive_pbkdf2_key("passphrase", &salt, 1_001).unwrap()
+        );
+        assert!(derive_pbkdf2_key("passphrase", &salt, 0).is_err());
+    }
+
+    #[test]
+    fn pbkdf2_matches_rfc_7914_vector() {
+        // PBKDF2-HMAC-SHA256 test vector from RFC 7914, section 11.
+        let key = derive_pbkdf2_key("passwd", b"salt", 1).unwrap();
+        assert_eq!(
+            key[..],
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44,
+                0xb6, 0x05, 0xf9, 0x41, 0x85, 0x21, 0x6d, 0xde, 0x04, 0x65, 0xe6, 0x8b, 0x9d, 0x57,
+                0xc2, 0x0d, 0xac, 0xbc,
+            ]
+        );
+    }
+
+    #[test]
+    fn kdf_pbkdf2_round_trips_through_the_store_header() {
+        let kdf = Kdf::pbkdf2(1_000);
+        let json = serde_json::to_value(&kdf).unwrap();
+        assert_eq!(json["algorithm"], "pbkdf2-sha256");
+        assert_eq!(json["iterations"], 1_000);
+        let stored: Kdf = serde_json::from_value(json).unwrap();
+
+        let first = CryptoManager::new("passphrase", &kdf).unwrap();
+        let second = CryptoManager::new("passphrase", &stored).unwrap();
+        let encrypted = first.encrypt("secret").unwrap();
+        assert_eq!(second.decrypt(&encrypted).unwrap(), "secret");
+        assert_eq!(stored.algorithm(), Some(KdfAlgorithm::Pbkdf2));
+    }
+
+    #[test]
+    fn kdf_renewed_keeps_algorithm_with_fresh_salt() {
+        let kdf = Kdf::pbkdf2(1_000);
+        let renewed = kdf.renewed();
+        assert!(matches!(
+            renewed,
+            Kdf::Pbkdf2Sha256 {
+                iterations: 1_000,
+                ..
+            }
+        ));
+        assert_ne!(renewed, kdf);
+        assert_eq!(
+            Kdf::LegacySha256.renewed().algorithm(),
+            Some(KdfAlgorithm::Argon2id)
+        );
+    }
+
+    #[test]
+    fn kdf_legacy_matches_plain_sha256() {
+        let key = Kdf::LegacySha256.derive("passphrase").unwrap();
+        let expected: [u8; 32] = Sha256::digest(b"passphrase").into();
+        assert_eq!(key, expected);
+    }
+
+    #[test]
+    fn kdf_rejects_corrupt_salt() {
+        let params = Argon2Params::default();
+        for salt in ["not base64!", "", "c2hvcnQ="] {
+            let kdf = Kdf::Argon2id {
+                salt: salt.to_string(),
+                params,
+            };
+            assert!(kdf.derive("passphrase").is_err());
+        }
+    }
+
+    #[test]
+    fn kdf_missing_field_defaults_to_legacy() {
+        let kdf: Kdf = serde_json::from_str(r#"{"algorithm":"legacy-sha256"}"#).unwrap();
+        assert_eq!(kdf, Kdf::LegacySha256);
+        assert!(serde_json::from_str::<Kdf>(r#"{"algorithm":"argon2id"}"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn askpass_uses_command_stdout() {
+        let key = read_master_key(&KeySource::Askpass("echo".to_string())).unwrap();
+        assert_eq!(key, MASTER_KEY_PROMPT);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn askpass_rejects_failure_and_empty_output() {
+        assert!(read_master_key(&KeySource::Askpass("false".to_string())).is_err());
+        assert!(read_master_key(&KeySource::Askpass("true".to_string())).is_err());
+        assert!(read_master_key(&KeySource::Askpass("/nonexistent/askpass".to_string())).is_err());
+    }
+
+    // Both cases share one test because they mutate the process environment.
+    #[test]
+    fn resolve_reads_and_clears_master_key_env() {
+        std::env::set_var(MASTER_KEY_ENV, "from-env");
+        let source = KeySource::resolve(None, false).unwrap();
+        assert!(matches!(&source, KeySource::Provided(key) if key == "from-env"));
+        assert!(std::env::var_os(MASTER_KEY_ENV).is_none());
+        assert_eq!(read_master_key(&source).unwrap(), "from-env");
+
+        std::env::set_var(MASTER_KEY_ENV, "from-env");
+        let source = KeySource::resolve(Some("/bin/askpass".to_string()), false).unwrap();
+        assert!(matches!(source, KeySource::Askpass(_)));
+        assert!(std::env::var_os(MASTER_KEY_ENV).is_none());
+    }
+
+    #[test]
+    fn read_master_key_rejects_empty_provided_key() {
+        assert!(read_master_key(&KeySource::Provided(String::new())).is_err());
+    }
 }
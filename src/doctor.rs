@@ -0,0 +1,322 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::config::{self, Config};
+use crate::storage::{self, StorageError};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run because an earlier check failed.
+    Skip,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Checks the config file and the store of `profile`. `read_key` is only
+/// called once the store file is known to parse, so a broken store does not
+/// ask for a master key it cannot use.
+pub fn diagnose(
+    profile: Option<&str>,
+    read_key: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Vec<Check> {
+    let (config, config_check) = match (config::config_path(), Config::load()) {
+        (_, Err(error)) => (
+            Config::default(),
+            Check::new("Config file", Status::Fail, format!("{error:#}")),
+        ),
+        (Some(path), Ok(config)) if path.exists() => (
+            config,
+            Check::new("Config file", Status::Pass, path.display().to_string()),
+        ),
+        (_, Ok(config)) => (
+            config,
+            Check::new("Config file", Status::Pass, "none, using the defaults"),
+        ),
+    };
+
+    let mut checks = vec![config_check];
+    match storage::store_path(&config, profile) {
+        Ok(path) => checks.extend(diagnose_store(&config.data_dir(), &path, read_key)),
+        Err(error) => checks.push(Check::new("Store file", Status::Fail, format!("{error:#}"))),
+    }
+    checks
+}
+
+/// Checks the data directory and the store file at `path`, then whether the
+/// master key from `read_key` unlocks it. Nothing is written.
+pub fn diagnose_store(
+    data_dir: &Path,
+    path: &Path,
+    read_key: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    checks.push(match fs::metadata(data_dir) {
+        Ok(metadata) if !metadata.is_dir() => Check::new(
+            "Data directory",
+            Status::Fail,
+            format!("{} is not a directory", data_dir.display()),
+        ),
+        Ok(metadata) if metadata.permissions().readonly() => Check::new(
+            "Data directory",
+            Status::Fail,
+            format!("{} is read-only", data_dir.display()),
+        ),
+        Ok(_) => Check::new(
+            "Data directory",
+            Status::Pass,
+            data_dir.display().to_string(),
+        ),
+        Err(error) => Check::new(
+            "Data directory",
+            Status::Fail,
+            format!("{}: {error}", data_dir.display()),
+        ),
+    });
+
+    let skip = |checks: &mut Vec<Check>, names: &[&'static str]| {
+        for name in names {
+            checks.push(Check::new(name, Status::Skip, "needs a readable store"));
+        }
+    };
+
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            checks.push(Check::new(
+                "Store file",
+                Status::Warn,
+                format!(
+                    "{} does not exist yet, it is created when a token is added",
+                    path.display()
+                ),
+            ));
+            skip(&mut checks, &["Store format", "Master key", "Tokens"]);
+            return checks;
+        }
+        Err(error) => {
+            checks.push(Check::new(
+                "Store file",
+                Status::Fail,
+                format!("{}: {error}", path.display()),
+            ));
+            skip(&mut checks, &["Store format", "Master key", "Tokens"]);
+            return checks;
+        }
+    };
+    let readonly = fs::metadata(path).is_ok_and(|metadata| metadata.permissions().readonly());
+    checks.push(if readonly {
+        Check::new(
+            "Store file",
+            Status::Warn,
+            format!("{} is read-only, changes cannot be saved", path.display()),
+        )
+    } else {
+        Check::new(
+            "Store file",
+            Status::Pass,
+            format!("{} ({} bytes)", path.display(), content.len()),
+        )
+    });
+
+    match storage::read_store_header(&content) {
+        Ok(header) => checks.push(Check::new(
+            "Store format",
+            Status::Pass,
+            format!(
+                "revision {}, {} value(s), key derived with {}",
+                header.revision, header.values, header.kdf
+            ),
+        )),
+        Err(error) => {
+            checks.push(Check::new(
+                "Store format",
+                Status::Fail,
+                format!("{error:#}"),
+            ));
+            skip(&mut checks, &["Master key", "Tokens"]);
+            return checks;
+        }
+    }
+
+    let counted = read_key().and_then(|key| storage::count_entries(&content, &key));
+    match counted {
+        Ok((tokens, aliases)) => {
+            checks.push(Check::new("Master key", Status::Pass, "unlocks the store"));
+            checks.push(Check::new(
+                "Tokens",
+                Status::Pass,
+                format!("{tokens} token(s), {aliases} alias(es)"),
+            ));
+        }
+        Err(error) if error.downcast_ref() == Some(&StorageError::IntegrityCheckFailed) => {
+            checks.push(Check::new("Master key", Status::Pass, "unlocks the store"));
+            checks.push(Check::new("Tokens", Status::Fail, error.to_string()));
+        }
+        Err(error) => {
+            let detail = match error.downcast_ref() {
+                Some(StorageError::WrongMasterKey) => "does not unlock the store".to_string(),
+                _ => format!("{error:#}"),
+            };
+            checks.push(Check::new("Master key", Status::Fail, detail));
+            checks.push(Check::new("Tokens", Status::Skip, "needs the master key"));
+        }
+    }
+    checks
+}
+
+/// One `[STATUS] name: detail` line per check.
+pub fn render(checks: &[Check]) -> String {
+    checks
+        .iter()
+        .map(|check| {
+            format!(
+                "[{}] {}: {}\n",
+                check.status.label(),
+                check.name,
+                check.detail
+            )
+        })
+        .collect()
+}
+
+pub fn all_passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.status != Status::Fail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Kdf;
+    use crate::storage::{AddOptions, TokenStorage};
+
+    fn key(passphrase: &str) -> impl FnOnce() -> Result<Zeroizing<String>> + '_ {
+        move || Ok(Zeroizing::new(passphrase.to_string()))
+    }
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("srs_doctor_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn render_prints_one_line_per_check() {
+        let checks = [
+            Check::new("Store format", Status::Pass, "revision 2"),
+            Check::new("Master key", Status::Fail, "does not unlock the store"),
+            Check::new("Tokens", Status::Skip, "needs the master key"),
+        ];
+        assert_eq!(
+            render(&checks),
+            "[PASS] Store format: revision 2\n\
+             [FAIL] Master key: does not unlock the store\n\
+             [SKIP] Tokens: needs the master key\n"
+        );
+        assert!(!all_passed(&checks));
+    }
+
+    #[test]
+    fn diagnose_store_reports_a_healthy_store() {
+        let dir = temp_dir();
+        let path = dir.join("srs.json");
+        let mut storage =
+            TokenStorage::open_with_kdf(&path, "passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage
+            .add_token("github", "ghp_abc", &AddOptions::default())
+            .unwrap();
+        storage.add_alias("GH_TOKEN", "github").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let checks = diagnose_store(&dir, &path, key("passphrase"));
+        let rendered = render(&checks);
+        assert!(all_passed(&checks), "{rendered}");
+        assert!(rendered.contains(
+            "[PASS] Store format: revision 2, 1 value(s), key derived with PBKDF2-HMAC-SHA256, 1000 iteration(s)\n"
+        ));
+        assert!(rendered.contains("[PASS] Master key: unlocks the store\n"));
+        assert!(rendered.ends_with("[PASS] Tokens: 1 token(s), 1 alias(es)\n"));
+        assert_eq!(fs::read(&path).unwrap(), before);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn diagnose_store_reports_a_wrong_key() {
+        let dir = temp_dir();
+        let path = dir.join("srs.json");
+        let mut storage =
+            TokenStorage::open_with_kdf(&path, "passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage
+            .add_token("github", "ghp_abc", &AddOptions::default())
+            .unwrap();
+
+        let checks = diagnose_store(&dir, &path, key("wrong"));
+        assert!(!all_passed(&checks));
+        assert!(render(&checks).ends_with(
+            "[FAIL] Master key: does not unlock the store\n[SKIP] Tokens: needs the master key\n"
+        ));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn diagnose_store_does_not_ask_for_a_key_for_a_corrupt_store() {
+        let dir = temp_dir();
+        let path = dir.join("srs.json");
+        fs::write(&path, b"{not json").unwrap();
+
+        let checks = diagnose_store(&dir, &path, || panic!("asked for the key"));
+        let statuses = checks.iter().map(|check| check.status).collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                Status::Pass,
+                Status::Pass,
+                Status::Fail,
+                Status::Skip,
+                Status::Skip
+            ]
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn diagnose_store_warns_about_a_missing_store() {
+        let dir = temp_dir();
+        let checks = diagnose_store(&dir, &dir.join("srs.json"), || panic!("asked for the key"));
+        assert!(all_passed(&checks));
+        assert_eq!(checks[1].status, Status::Warn);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
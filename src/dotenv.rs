@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+
+pub fn parse(content: &str) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid line {} in env file: expected NAME=value",
+                index + 1
+            )
+        })?;
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!(
+                "Invalid line {} in env file: empty name",
+                index + 1
+            ));
+        }
+        entries.push((name.to_string(), parse_value(value.trim(), index + 1)?));
+    }
+    Ok(entries)
+}
+
+/// Whether `name` can be written as a `.env` key: a letter or underscore
+/// followed by letters, digits and underscores.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Writes one `NAME=value` line per entry, in order, quoting values so that
+/// `parse` reads them back unchanged.
+pub fn render(entries: &[(String, String)]) -> String {
+    let mut output = String::new();
+    for (name, value) in entries {
+        output.push_str(name);
+        output.push('=');
+        output.push_str(&quote(value));
+        output.push('\n');
+    }
+    output
+}
+
+/// Leaves simple values bare, single-quotes values without quotes or line
+/// breaks so nothing inside is interpreted, and double-quotes the rest with
+/// backslash escapes.
+fn quote(value: &str) -> String {
+    let bare = |c: char| c.is_ascii_alphanumeric() || "_-./:@+,%".contains(c);
+    if value.chars().all(bare) {
+        return value.to_string();
+    }
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{value}'");
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_value(raw: &str, line: usize) -> Result<String> {
+    if let Some(rest) = raw.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| anyhow!("Unterminated quote on line {line} in env file"))?;
+        return Ok(rest[..end].to_string());
+    }
+
+    if let Some(rest) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Ok(value),
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(other) => value.push(other),
+                    None => break,
+                },
+                _ => value.push(c),
+            }
+        }
+        return Err(anyhow!("Unterminated quote on line {line} in env file"));
+    }
+
+    let value = match raw.find(" #") {
+        Some(comment) => &raw[..comment],
+        None => raw,
+    };
+    Ok(value.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let content = "# project config\n\nDATABASE_URL=postgres://localhost\nexport DEBUG=1\n";
+        let entries = parse(content).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "DATABASE_URL".to_string(),
+                    "postgres://localhost".to_string()
+                ),
+                ("DEBUG".to_string(), "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_handles_quoted_values() {
+        let content = "A='single # not a comment'\nB=\"line\\nbreak \\\"quoted\\\"\"\nC=plain # trailing comment\n";
+        let entries = parse(content).unwrap();
+        assert_eq!(entries[0].1, "single # not a comment");
+        assert_eq!(entries[1].1, "line\nbreak \"quoted\"");
+        assert_eq!(entries[2].1, "plain");
+    }
+
+    #[test]
+    fn render_quotes_values_that_need_it() {
+        let entries = vec![
+            (
+                "PLAIN".to_string(),
+                "postgres://user@localhost:5432/db".to_string(),
+            ),
+            (
+                "SPACES".to_string(),
+                "two words # not a comment".to_string(),
+            ),
+            ("QUOTE".to_string(), "it's \"here\"\\".to_string()),
+            ("MULTILINE".to_string(), "line one\nline two".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ];
+        assert_eq!(
+            render(&entries),
+            "PLAIN=postgres://user@localhost:5432/db\n\
+             SPACES='two words # not a comment'\n\
+             QUOTE=\"it's \\\"here\\\"\\\\\"\n\
+             MULTILINE=\"line one\\nline two\"\n\
+             EMPTY=\n"
+        );
+        assert_eq!(parse(&render(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn sample_env_round_trips_identically() {
+        let sample = "API_KEY=sk_live_123\n\
+                      DATABASE_URL=postgres://localhost/app\n\
+                      GREETING='hello $USER'\n\
+                      PEM=\"-----BEGIN KEY-----\\nabc\\n-----END KEY-----\"\n";
+        assert_eq!(render(&parse(sample).unwrap()), sample);
+    }
+
+    #[test]
+    fn is_valid_name_follows_shell_rules() {
+        assert!(is_valid_name("GITHUB_TOKEN"));
+        assert!(is_valid_name("_private1"));
+        assert!(!is_valid_name("1PASSWORD"));
+        assert!(!is_valid_name("git:github.com"));
+        assert!(!is_valid_name(""));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        assert!(parse("NO_EQUALS_SIGN").is_err());
+        assert!(parse("=value").is_err());
+        assert!(parse("A=\"unterminated").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use clap::ValueEnum;
+use rand::Rng;
+
+pub const DEFAULT_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum Charset {
+    #[default]
+    Alphanumeric,
+    Hex,
+    /// Every printable ASCII character except space.
+    AsciiSymbols,
+}
+
+impl Charset {
+    pub fn chars(self) -> Vec<char> {
+        match self {
+            Charset::Alphanumeric => ('a'..='z').chain('A'..='Z').chain('0'..='9').collect(),
+            Charset::Hex => ('0'..='9').chain('a'..='f').collect(),
+            Charset::AsciiSymbols => ('!'..='~').collect(),
+        }
+    }
+}
+
+/// Draws `length` characters uniformly from `charset` with the thread-local
+/// CSPRNG.
+pub fn generate(length: usize, charset: Charset) -> String {
+    let chars = charset.chars();
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| chars[rng.gen_range(0..chars.len())])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_has_the_requested_length() {
+        assert_eq!(generate(DEFAULT_LENGTH, Charset::default()).len(), 32);
+        assert_eq!(generate(7, Charset::Hex).len(), 7);
+        assert!(generate(0, Charset::Hex).is_empty());
+    }
+
+    #[test]
+    fn generate_only_uses_the_requested_charset() {
+        let value = generate(256, Charset::Alphanumeric);
+        assert!(value.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        let value = generate(256, Charset::Hex);
+        assert!(value
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)));
+
+        let value = generate(256, Charset::AsciiSymbols);
+        assert!(value.chars().all(|c| c.is_ascii_graphic()));
+    }
+
+    #[test]
+    fn ascii_symbols_include_punctuation() {
+        let chars = Charset::AsciiSymbols.chars();
+        assert_eq!(chars.len(), 94);
+        assert!(chars.contains(&'!') && chars.contains(&'~'));
+    }
+
+    #[test]
+    fn generate_varies_between_calls() {
+        assert_ne!(
+            generate(32, Charset::Alphanumeric),
+            generate(32, Charset::Alphanumeric)
+        );
+    }
+}
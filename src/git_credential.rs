@@ -0,0 +1,190 @@
+use crate::storage::TokenStorage;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+
+/// The operations git passes to a credential helper.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Operation {
+    Get,
+    Store,
+    Erase,
+}
+
+/// The attributes git writes to a helper's stdin, one `key=value` per line.
+/// Attributes srs does not use are ignored.
+#[derive(Default, PartialEq, Debug)]
+pub struct CredentialRequest {
+    pub protocol: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub fn parse(input: &str) -> Result<CredentialRequest> {
+    let mut request = CredentialRequest::default();
+    for line in input.lines() {
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid credential line '{line}': expected key=value"))?;
+        let value = Some(value.to_string());
+        match key {
+            "protocol" => request.protocol = value,
+            "host" => request.host = value,
+            "path" => request.path = value,
+            "username" => request.username = value,
+            "password" => request.password = value,
+            _ => {}
+        }
+    }
+    Ok(request)
+}
+
+impl CredentialRequest {
+    /// Token names to look up, most specific first: `git:<user>@<host>` when
+    /// git already knows the username, then `git:<host>`.
+    pub fn token_names(&self) -> Vec<String> {
+        let Some(host) = &self.host else {
+            return Vec::new();
+        };
+        let mut names = Vec::new();
+        if let Some(username) = &self.username {
+            names.push(format!("git:{username}@{host}"));
+        }
+        names.push(format!("git:{host}"));
+        names
+    }
+}
+
+/// Answers a `get` request read from `input`. The output is empty when no
+/// token matches, which tells git to try its other helpers or prompt.
+pub fn answer_get(
+    storage: &TokenStorage,
+    input: &str,
+    default_username: Option<&str>,
+) -> Result<String> {
+    let request = parse(input)?;
+    for name in request.token_names() {
+        if let Some(token) = storage.get_token(&name)? {
+            let username = request.username.as_deref().or(default_username);
+            return render(username, &token);
+        }
+    }
+    Ok(String::new())
+}
+
+/// Renders the answer to a `get`. Git reads one attribute per line, so values
+/// containing a newline or NUL cannot be passed back.
+pub fn render(username: Option<&str>, password: &str) -> Result<String> {
+    let mut output = String::new();
+    for (key, value) in [("username", username), ("password", Some(password))] {
+        let Some(value) = value else {
+            continue;
+        };
+        if value.contains(['\n', '\0']) {
+            return Err(anyhow!(
+                "The {key} contains a newline or NUL and cannot be passed to git."
+            ));
+        }
+        output.push_str(&format!("{key}={value}\n"));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET_INPUT: &str = "protocol=https\nhost=github.com\nwwwauth[]=Basic realm=\"GitHub\"\n\n";
+
+    #[test]
+    fn parse_reads_known_attributes() {
+        let request = parse(GET_INPUT).unwrap();
+        assert_eq!(
+            request,
+            CredentialRequest {
+                protocol: Some("https".to_string()),
+                host: Some("github.com".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_keeps_equals_signs_in_values() {
+        let request = parse("username=me\npassword=a=b\n").unwrap();
+        assert_eq!(request.password.as_deref(), Some("a=b"));
+    }
+
+    #[test]
+    fn parse_rejects_lines_without_a_key() {
+        assert!(parse("protocol=https\ngarbage\n").is_err());
+    }
+
+    #[test]
+    fn token_names_prefer_the_username() {
+        assert_eq!(parse(GET_INPUT).unwrap().token_names(), ["git:github.com"]);
+
+        let request = parse("host=gitlab.com:8443\nusername=ci\n").unwrap();
+        assert_eq!(
+            request.token_names(),
+            ["git:ci@gitlab.com:8443", "git:gitlab.com:8443"]
+        );
+        assert!(parse("protocol=https\n").unwrap().token_names().is_empty());
+    }
+
+    #[test]
+    fn render_emits_username_and_password() {
+        assert_eq!(
+            render(Some("x-access-token"), "ghp_secret").unwrap(),
+            "username=x-access-token\npassword=ghp_secret\n"
+        );
+        assert_eq!(render(None, "ghp_secret").unwrap(), "password=ghp_secret\n");
+    }
+
+    fn storage() -> TokenStorage {
+        let path = std::env::temp_dir().join(format!("srs_git_test_{}.json", uuid::Uuid::new_v4()));
+        let mut storage =
+            TokenStorage::with_crypto_manager(path, crate::CryptoManager::from_key([0; 32]))
+                .unwrap();
+        let options = crate::AddOptions::default();
+        storage
+            .add_token("git:github.com", "ghp_shared", &options)
+            .unwrap();
+        storage
+            .add_token("git:ci@github.com", "ghp_ci", &options)
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn answer_get_returns_the_host_token() {
+        assert_eq!(
+            answer_get(&storage(), GET_INPUT, Some("x-access-token")).unwrap(),
+            "username=x-access-token\npassword=ghp_shared\n"
+        );
+    }
+
+    #[test]
+    fn answer_get_prefers_the_users_token() {
+        let input = "protocol=https\nhost=github.com\nusername=ci\n\n";
+        assert_eq!(
+            answer_get(&storage(), input, Some("x-access-token")).unwrap(),
+            "username=ci\npassword=ghp_ci\n"
+        );
+    }
+
+    #[test]
+    fn answer_get_is_empty_for_unknown_hosts() {
+        let input = "protocol=https\nhost=gitlab.com\n\n";
+        assert_eq!(answer_get(&storage(), input, None).unwrap(), "");
+    }
+
+    #[test]
+    fn render_refuses_multiline_values() {
+        assert!(render(None, "line one\nline two").is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::storage::{TokenDump, TokenMetadata};
+
+/// Where `srs import` reads tokens from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum ImportFormat {
+    /// A password-protected backup written by `srs export`.
+    Srs,
+    /// A `pass` password store directory, decrypted with `gpg`.
+    Pass,
+    /// A `.netrc` file.
+    Netrc,
+    /// A CSV export with a header row, e.g. from 1Password or Bitwarden.
+    Csv,
+}
+
+fn entry(name: String, value: String, note: Option<String>) -> TokenDump {
+    TokenDump {
+        name,
+        value,
+        metadata: note.map(|note| TokenMetadata {
+            note: Some(note),
+            ..TokenMetadata::new()
+        }),
+    }
+}
+
+/// Walks a `pass` store. Each `.gpg` file becomes a token named after its
+/// path without the extension (`email/work`); following the `pass`
+/// convention the first line is the secret and any further lines become
+/// the note. Hidden directories such as `.git` are skipped.
+pub fn read_password_store(
+    dir: &Path,
+    mut decrypt: impl FnMut(&Path) -> Result<Zeroizing<String>>,
+) -> Result<Vec<TokenDump>> {
+    let mut files = Vec::new();
+    collect_gpg_files(dir, &mut files)?;
+    files.sort();
+
+    let mut entries = Vec::new();
+    for file in files {
+        let relative = file.strip_prefix(dir).unwrap_or(&file).with_extension("");
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let content = decrypt(&file)?;
+        let (secret, rest) = content.split_once('\n').unwrap_or((&content, ""));
+        let secret = secret.trim_end_matches('\r');
+        if secret.is_empty() {
+            continue;
+        }
+        let note = Some(rest.trim().to_string()).filter(|note| !note.is_empty());
+        entries.push(entry(name, secret.to_string(), note));
+    }
+    Ok(entries)
+}
+
+fn collect_gpg_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    let listing =
+        fs::read_dir(dir).map_err(|e| anyhow!("Could not read '{}': {e}", dir.display()))?;
+    for item in listing {
+        let path = item?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            collect_gpg_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "gpg") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts one `pass` entry the way `pass show` does.
+pub fn gpg_decrypt(path: &Path) -> Result<Zeroizing<String>> {
+    let output = std::process::Command::new("gpg")
+        .args(["--quiet", "--batch", "--decrypt"])
+        .arg(path)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("Could not run gpg: {e}"))?;
+    let content = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(anyhow!("gpg could not decrypt '{}'", path.display()));
+    }
+    Ok(Zeroizing::new(
+        String::from_utf8(content.to_vec())
+            .map_err(|_| anyhow!("'{}' does not decrypt to UTF-8 text", path.display()))?,
+    ))
+}
+
+/// Reads the `machine`/`login`/`password` entries of a `.netrc` file. Each
+/// machine with a password becomes a token named after the host, with the
+/// login kept in the note. `default` entries and `macdef` bodies are skipped.
+pub fn parse_netrc(content: &str) -> Result<Vec<TokenDump>> {
+    struct Machine {
+        host: String,
+        login: Option<String>,
+        password: Option<String>,
+    }
+
+    let mut machines = Vec::new();
+    let mut current: Option<Machine> = None;
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let line = line.split_once('#').map_or(line, |(before, _)| before);
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            let mut value = |key: &str| {
+                words
+                    .next()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("Invalid .netrc: '{key}' without a value"))
+            };
+            match word {
+                "machine" => {
+                    machines.extend(current.take());
+                    current = Some(Machine {
+                        host: value(word)?,
+                        login: None,
+                        password: None,
+                    });
+                }
+                "default" => machines.extend(current.take()),
+                "login" => {
+                    let login = value(word)?;
+                    if let Some(machine) = current.as_mut() {
+                        machine.login = Some(login);
+                    }
+                }
+                "password" => {
+                    let password = value(word)?;
+                    if let Some(machine) = current.as_mut() {
+                        machine.password = Some(password);
+                    }
+                }
+                "account" => {
+                    value(word)?;
+                }
+                "macdef" => {
+                    value(word)?;
+                    // A macro runs until the next empty line.
+                    for line in lines.by_ref() {
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                other => return Err(anyhow!("Invalid .netrc: unexpected '{other}'")),
+            }
+        }
+    }
+    machines.extend(current);
+
+    Ok(machines
+        .into_iter()
+        .filter_map(|machine| {
+            let note = machine.login.map(|login| format!("login: {login}"));
+            Some(entry(machine.host, machine.password?, note))
+        })
+        .collect())
+}
+
+/// Reads a CSV export with a header row. Column names are matched ignoring
+/// case; rows with an empty name or secret, such as secure notes, are skipped.
+pub fn parse_csv(content: &str, name_column: &str, secret_column: &str) -> Result<Vec<TokenDump>> {
+    let mut rows = parse_csv_rows(content)?.into_iter();
+    let header = rows
+        .next()
+        .ok_or_else(|| anyhow!("The CSV file is empty"))?;
+    let column = |wanted: &str| {
+        header
+            .iter()
+            .position(|name| name.trim().eq_ignore_ascii_case(wanted))
+            .ok_or_else(|| anyhow!("The CSV file has no '{wanted}' column"))
+    };
+    let name_index = column(name_column)?;
+    let secret_index = column(secret_column)?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        let field = |index: usize| row.get(index).map_or("", String::as_str);
+        let (name, secret) = (field(name_index).trim(), field(secret_index));
+        if name.is_empty() || secret.is_empty() {
+            continue;
+        }
+        entries.push(entry(name.to_string(), secret.to_string(), None));
+    }
+    Ok(entries)
+}
+
+/// Splits RFC 4180 CSV into rows: fields may be double-quoted, quotes inside
+/// them are doubled, and quoted fields may span lines.
+fn parse_csv_rows(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(anyhow!("The CSV file has an unterminated quoted field"));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows.retain(|row| !(row.len() == 1 && row[0].is_empty()));
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(entries: &[TokenDump]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.value.as_str()))
+            .collect()
+    }
+
+    fn note(entry: &TokenDump) -> Option<&str> {
+        entry.metadata.as_ref()?.note.as_deref()
+    }
+
+    const NETRC: &str = "\
+# personal hosts
+machine github.com login octocat password ghp_secret
+machine api.example.com
+    login deploy
+    password s3cret
+machine nopass.example.com login someone
+
+macdef init
+cd /pub
+quit
+
+default login anonymous password guest
+";
+
+    #[test]
+    fn netrc_reads_machines_with_passwords() {
+        let entries = parse_netrc(NETRC).unwrap();
+        assert_eq!(
+            pairs(&entries),
+            [("github.com", "ghp_secret"), ("api.example.com", "s3cret"),]
+        );
+        assert_eq!(note(&entries[0]), Some("login: octocat"));
+    }
+
+    #[test]
+    fn netrc_rejects_a_key_without_a_value() {
+        assert!(parse_netrc("machine github.com password").is_err());
+        assert!(parse_netrc("machine github.com token x").is_err());
+    }
+
+    const ONEPASSWORD_CSV: &str = "\
+Title,Website,Username,Password,Notes\r
+GitHub,https://github.com,octocat,\"pa,ss\"\"word\",\r
+Secure note,,,,\"multi
+line\"\r
+AWS,https://aws.amazon.com,admin,aws_secret,prod\r
+";
+
+    #[test]
+    fn csv_maps_the_name_and_secret_columns() {
+        let entries = parse_csv(ONEPASSWORD_CSV, "title", "PASSWORD").unwrap();
+        assert_eq!(
+            pairs(&entries),
+            [("GitHub", "pa,ss\"word"), ("AWS", "aws_secret")]
+        );
+        let by_user = parse_csv(ONEPASSWORD_CSV, "username", "password").unwrap();
+        assert_eq!(by_user[0].name, "octocat");
+    }
+
+    #[test]
+    fn csv_reports_missing_columns_and_broken_quotes() {
+        let error = parse_csv(ONEPASSWORD_CSV, "name", "password")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "The CSV file has no 'name' column");
+        assert!(parse_csv("name,password\nx,\"open\n", "name", "password").is_err());
+        assert!(parse_csv("", "name", "password").is_err());
+    }
+
+    #[test]
+    fn password_store_walks_gpg_files() {
+        let dir = std::env::temp_dir().join(format!("srs_pass_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("email")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("github.gpg"), "ghp_secret\n").unwrap();
+        fs::write(dir.join("email/work.gpg"), "hunter2\nuser: me\nurl: mail\n").unwrap();
+        fs::write(dir.join(".git/HEAD.gpg"), "ignored").unwrap();
+        fs::write(dir.join(".gpg-id"), "ABCDEF").unwrap();
+        fs::write(dir.join("empty.gpg"), "").unwrap();
+
+        // The fixtures are plaintext, so "decrypting" is just reading them.
+        let entries =
+            read_password_store(&dir, |path| Ok(Zeroizing::new(fs::read_to_string(path)?)))
+                .unwrap();
+        assert_eq!(
+            pairs(&entries),
+            [("email/work", "hunter2"), ("github", "ghp_secret")]
+        );
+        assert_eq!(note(&entries[0]), Some("user: me\nurl: mail"));
+        assert_eq!(note(&entries[1]), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn password_store_stops_at_a_failed_decryption() {
+        let dir = std::env::temp_dir().join(format!("srs_pass_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("github.gpg"), "x").unwrap();
+        let result = read_password_store(&dir, |_| Err(anyhow!("no secret key")));
+        assert_eq!(result.err().unwrap().to_string(), "no secret key");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,34 @@
+//! Secure Rust Storage: an encrypted store for personal access tokens.
+//!
+//! The `srs` binary is a thin command line front end over this library. The
+//! entry point is [`TokenStorage`], opened either from a passphrase or from a
+//! [`CryptoManager`] holding an already derived key:
+//!
+//! ```no_run
+//! use srs::{AddOptions, TokenStorage};
+//!
+//! let mut storage = TokenStorage::open("tokens.json", "master key")?;
+//! storage.add_token("github_token", "ghp_example", &AddOptions::default())?;
+//! assert_eq!(storage.get_token("github_token")?.as_deref(), Some("ghp_example"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Errors are [`anyhow::Error`]s; the failures worth telling apart are
+//! [`StorageError`] values and can be recovered with `downcast_ref`.
+
+pub mod audit;
+pub mod config;
+pub mod crypto;
+pub mod doctor;
+pub mod dotenv;
+pub mod generate;
+pub mod git_credential;
+pub mod import;
+pub mod log;
+pub mod seal;
+pub mod shell_export;
+pub mod storage;
+pub mod sync;
+
+pub use crypto::{CryptoManager, KeySource};
+pub use storage::{AddOptions, StorageError, TokenStorage};
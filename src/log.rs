@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much status output srs prints. Errors, warnings and the results a
+/// command was asked for are printed at every level.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Quiet,
+        1 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+/// Whether a message of `message` level is shown when running at `current`.
+pub fn shows(current: Level, message: Level) -> bool {
+    message <= current
+}
+
+pub fn enabled(message: Level) -> bool {
+    shows(level(), message)
+}
+
+/// Success and progress messages on stdout, hidden by `--quiet`.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Like `info!`, on stderr so it stays out of output that may be piped.
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Diagnostics on stderr, only shown with `--verbose`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Verbose) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hides_status_and_diagnostics() {
+        assert!(shows(Level::Quiet, Level::Quiet));
+        assert!(!shows(Level::Quiet, Level::Normal));
+        assert!(!shows(Level::Quiet, Level::Verbose));
+    }
+
+    #[test]
+    fn normal_hides_diagnostics_only() {
+        assert!(shows(Level::Normal, Level::Normal));
+        assert!(!shows(Level::Normal, Level::Verbose));
+    }
+
+    #[test]
+    fn verbose_shows_everything() {
+        assert!(shows(Level::Verbose, Level::Quiet));
+        assert!(shows(Level::Verbose, Level::Normal));
+        assert!(shows(Level::Verbose, Level::Verbose));
+    }
+
+    #[test]
+    fn set_level_round_trips() {
+        for expected in [Level::Quiet, Level::Verbose, Level::Normal] {
+            set_level(expected);
+            assert_eq!(level(), expected);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::crypto::{self, Argon2Params, CryptoManager};
+use crate::storage::TokenDump;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const SEAL_FORMAT: &str = "srs-seal";
+const BACKUP_FORMAT: &str = "srs-backup";
+const SEAL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SealedBundle {
+    format: String,
+    version: u32,
+    salt: String,
+    kdf: Argon2Params,
+    payload: String,
+}
+
+/// The two kinds of sealed file srs writes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SealedKind {
+    /// Selected tokens written by `seal`.
+    Bundle,
+    /// The whole store written by `export`.
+    Backup,
+}
+
+impl SealedKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            SealedKind::Bundle => "sealed bundle",
+            SealedKind::Backup => "backup",
+        }
+    }
+}
+
+/// What kind of sealed file `content` is, judging by its header only;
+/// `None` if it is not a sealed file at all.
+pub fn sealed_kind(content: &str) -> Option<SealedKind> {
+    #[derive(Deserialize)]
+    struct Header {
+        format: String,
+    }
+    match serde_json::from_str::<Header>(content)
+        .ok()?
+        .format
+        .as_str()
+    {
+        SEAL_FORMAT => Some(SealedKind::Bundle),
+        BACKUP_FORMAT => Some(SealedKind::Backup),
+        _ => None,
+    }
+}
+
+/// A full copy of the store, written by `export` and read back by `import`.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub tokens: Vec<TokenDump>,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+pub fn seal(entries: &[TokenDump], password: &str) -> Result<String> {
+    seal_payload(SEAL_FORMAT, entries, password)
+}
+
+pub fn unseal(content: &str, password: &str) -> Result<Vec<TokenDump>> {
+    unseal_payload(SEAL_FORMAT, "sealed bundle", content, password)
+}
+
+pub fn seal_backup(backup: &Backup, password: &str) -> Result<String> {
+    seal_payload(BACKUP_FORMAT, backup, password)
+}
+
+pub fn unseal_backup(content: &str, password: &str) -> Result<Backup> {
+    unseal_payload(BACKUP_FORMAT, "backup", content, password)
+}
+
+fn seal_payload<T: Serialize + ?Sized>(
+    format: &str,
+    payload: &T,
+    password: &str,
+) -> Result<String> {
+    let salt = crypto::random_salt();
+    let kdf = Argon2Params::default();
+    let key = crypto::derive_argon2_key(password, &salt, &kdf)?;
+
+    let plaintext = serde_json::to_string(payload)?;
+    let bundle = SealedBundle {
+        format: format.to_string(),
+        version: SEAL_VERSION,
+        salt: general_purpose::STANDARD.encode(salt),
+        kdf,
+        payload: CryptoManager::from_key(key).encrypt(&plaintext)?,
+    };
+    Ok(serde_json::to_string_pretty(&bundle)?)
+}
+
+fn unseal_payload<T: DeserializeOwned>(
+    format: &str,
+    label: &str,
+    content: &str,
+    password: &str,
+) -> Result<T> {
+    let bundle: SealedBundle =
+        serde_json::from_str(content).map_err(|e| anyhow!("Not an srs {label}: {e}"))?;
+    if bundle.format != format {
+        return Err(anyhow!("Not an srs {label}."));
+    }
+    if bundle.version != SEAL_VERSION {
+        return Err(anyhow!(
+            "The {label} has version {}, which is not supported by this srs, please upgrade.",
+            bundle.version
+        ));
+    }
+
+    let salt = general_purpose::STANDARD
+        .decode(&bundle.salt)
+        .map_err(|e| anyhow!("The {label} has a corrupt salt: {e}"))?;
+    let key = crypto::derive_argon2_key(password, &salt, &bundle.kdf)?;
+    let plaintext = CryptoManager::from_key(key)
+        .decrypt(&bundle.payload)
+        .map_err(|_| anyhow!("Wrong password or the {label} was tampered with."))?;
+    Ok(serde_json::from_str(&plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_entries() -> Vec<TokenDump> {
+        vec![
+            TokenDump {
+                name: "GITHUB_TOKEN".to_string(),
+                value: "ghp_secret".to_string(),
+                metadata: None,
+            },
+            TokenDump {
+                name: "NPM_TOKEN".to_string(),
+                value: "npm_secret".to_string(),
+                metadata: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn seal_and_unseal_round_trip() {
+        let sealed = seal(&sample_entries(), "hunter2").unwrap();
+        assert!(!sealed.contains("ghp_secret"));
+        assert!(!sealed.contains("GITHUB_TOKEN"));
+
+        let entries = unseal(&sealed, "hunter2").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "GITHUB_TOKEN");
+        assert_eq!(entries[0].value, "ghp_secret");
+        assert_eq!(entries[1].name, "NPM_TOKEN");
+        assert_eq!(entries[1].value, "npm_secret");
+    }
+
+    #[test]
+    fn unseal_with_wrong_password_fails() {
+        let sealed = seal(&sample_entries(), "hunter2").unwrap();
+        assert!(unseal(&sealed, "hunter3").is_err());
+    }
+
+    #[test]
+    fn unseal_detects_tampering() {
+        let sealed = seal(&sample_entries(), "hunter2").unwrap();
+        let mut bundle: SealedBundle = serde_json::from_str(&sealed).unwrap();
+        let mut payload = general_purpose::STANDARD.decode(&bundle.payload).unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 0x01;
+        bundle.payload = general_purpose::STANDARD.encode(payload);
+
+        let tampered = serde_json::to_string(&bundle).unwrap();
+        assert!(unseal(&tampered, "hunter2").is_err());
+    }
+
+    #[test]
+    fn unseal_rejects_unknown_versions() {
+        let sealed = seal(&sample_entries(), "hunter2").unwrap();
+        let mut bundle: SealedBundle = serde_json::from_str(&sealed).unwrap();
+        bundle.version = SEAL_VERSION + 1;
+
+        let future = serde_json::to_string(&bundle).unwrap();
+        let error = unseal(&future, "hunter2").err().unwrap();
+        assert!(error.to_string().contains("please upgrade"));
+    }
+
+    #[test]
+    fn backup_round_trip_keeps_aliases() {
+        let backup = Backup {
+            tokens: sample_entries(),
+            aliases: HashMap::from([("GH".to_string(), "GITHUB_TOKEN".to_string())]),
+        };
+        let sealed = seal_backup(&backup, "hunter2").unwrap();
+
+        let restored = unseal_backup(&sealed, "hunter2").unwrap();
+        assert_eq!(restored.tokens.len(), 2);
+        assert_eq!(restored.aliases["GH"], "GITHUB_TOKEN");
+    }
+
+    #[test]
+    fn sealed_kind_reads_the_header() {
+        let bundle = seal(&sample_entries(), "hunter2").unwrap();
+        let backup = seal_backup(
+            &Backup {
+                tokens: sample_entries(),
+                aliases: HashMap::new(),
+            },
+            "hunter2",
+        )
+        .unwrap();
+        assert_eq!(sealed_kind(&bundle), Some(SealedKind::Bundle));
+        assert_eq!(sealed_kind(&backup), Some(SealedKind::Backup));
+        assert_eq!(sealed_kind("{\"tokens\": {}}"), None);
+        assert_eq!(sealed_kind("not json"), None);
+    }
+
+    #[test]
+    fn backups_and_sealed_bundles_are_not_interchangeable() {
+        let sealed = seal(&sample_entries(), "hunter2").unwrap();
+        let error = unseal_backup(&sealed, "hunter2").err().unwrap();
+        assert!(error.to_string().contains("Not an srs backup"));
+    }
+}
@@ -0,0 +1,94 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ShellSyntax {
+    Sh,
+    Fish,
+    Powershell,
+}
+
+/// Renders one assignment per line, sorted by name, quoted so the output can
+/// be passed to `eval` (or `Invoke-Expression`) whatever the values contain.
+pub fn render(vars: &HashMap<String, String>, syntax: ShellSyntax) -> String {
+    let mut names = vars.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut output = String::new();
+    for name in names {
+        let value = &vars[name];
+        let line = match syntax {
+            ShellSyntax::Sh => format!("export {name}={}", quote_sh(value)),
+            ShellSyntax::Fish => format!("set -gx {name} {}", quote_fish(value)),
+            ShellSyntax::Powershell => format!("$env:{name} = {}", quote_powershell(value)),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+fn quote_sh(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn quote_fish(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+fn quote_powershell(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<String, String> {
+        HashMap::from([
+            ("B_TOKEN".to_string(), "it's $HOME `x` \\n".to_string()),
+            ("A_TOKEN".to_string(), "plain".to_string()),
+        ])
+    }
+
+    #[test]
+    fn render_sh_single_quotes_values() {
+        assert_eq!(
+            render(&vars(), ShellSyntax::Sh),
+            "export A_TOKEN='plain'\nexport B_TOKEN='it'\\''s $HOME `x` \\n'\n"
+        );
+    }
+
+    #[test]
+    fn render_fish_escapes_quotes_and_backslashes() {
+        assert_eq!(
+            render(&vars(), ShellSyntax::Fish),
+            "set -gx A_TOKEN 'plain'\nset -gx B_TOKEN 'it\\'s $HOME `x` \\\\n'\n"
+        );
+    }
+
+    #[test]
+    fn render_powershell_doubles_quotes() {
+        assert_eq!(
+            render(&vars(), ShellSyntax::Powershell),
+            "$env:A_TOKEN = 'plain'\n$env:B_TOKEN = 'it''s $HOME `x` \\n'\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_sh_round_trips_through_sh() {
+        let value = "a'b\"c $d `e` \\f\ng";
+        let vars = HashMap::from([("SRS_TEST_VALUE".to_string(), value.to_string())]);
+        let script = format!(
+            "{}printf %s \"$SRS_TEST_VALUE\"",
+            render(&vars, ShellSyntax::Sh)
+        );
+
+        let output = std::process::Command::new("sh")
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), value);
+    }
+}
@@ -1,274 +1,4891 @@
-use crate::crypto::CryptoManager;
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::config::Config;
+use crate::crypto::{self, CryptoManager, Kdf, KeySource};
+use crate::seal::{self, Backup};
 use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
 
-pub static CONFIG_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+pub static DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     let mut data_local_dir = dirs::data_local_dir().unwrap();
     data_local_dir.push("srs");
     let _ = fs::create_dir_all(&data_local_dir);
-    data_local_dir.push("srs.json");
     data_local_dir
 });
 
-#[derive(Serialize, Deserialize)]
+const STORE_FILE: &str = "srs.json";
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Failures callers are expected to tell apart; everything else is reported
+/// through plain `anyhow` errors. Recover the variant with `downcast_ref`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorageError {
+    NotFound(String),
+    AliasNotFound(String),
+    WrongMasterKey,
+    AlreadyExists(String),
+    /// The store's MAC does not match its contents: entries were changed,
+    /// added or removed outside srs.
+    IntegrityCheckFailed,
+    /// The value given for the token is empty or only whitespace.
+    EmptyValue(String),
+    /// Another srs process kept the store locked for longer than
+    /// [`LOCK_WAIT`].
+    Locked,
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(name) => write!(f, "Token '{name}' not found"),
+            StorageError::AliasNotFound(alias) => write!(f, "Alias '{alias}' not found"),
+            StorageError::WrongMasterKey => write!(f, "Incorrect master key."),
+            StorageError::AlreadyExists(name) => write!(f, "Token '{name}' already exists"),
+            StorageError::IntegrityCheckFailed => write!(
+                f,
+                "Store integrity check failed, the file was modified outside srs."
+            ),
+            StorageError::EmptyValue(name) => {
+                write!(f, "Refusing to store an empty value for '{name}'")
+            }
+            StorageError::Locked => write!(f, "The store is locked by another srs process."),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+#[derive(Default, Clone)]
 struct TokenDatabase {
-    tokens: HashMap<String, String>,
+    tokens: HashMap<String, TokenEntry>,
+    aliases: HashMap<String, String>,
+    revision: u64,
+    kdf: Kdf,
+    /// Set when the file still lists token names in plaintext.
+    plaintext_names: bool,
+    /// Set when the values were encrypted without their names as associated
+    /// data, as stores were before values were bound to their names.
+    unbound_values: bool,
+}
+
+impl TokenDatabase {
+    /// Every value is encrypted with its token name as associated data, so a
+    /// ciphertext moved under another name no longer decrypts.
+    fn value_aad<'a>(&self, name: &'a str) -> &'a [u8] {
+        if self.unbound_values {
+            &[]
+        } else {
+            name.as_bytes()
+        }
+    }
+}
+
+/// The store as written to disk. Values are keyed by opaque ids and the
+/// names, aliases and metadata live in an encrypted index, so the file only
+/// reveals how many tokens there are. `tokens` and `aliases` are only used by
+/// stores written before names were encrypted.
+#[derive(Serialize, Deserialize)]
+struct StoredDatabase {
+    #[serde(default)]
+    version: u32,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tokens: HashMap<String, TokenEntry>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    aliases: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    check: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    values: HashMap<String, String>,
+    #[serde(default)]
+    revision: u64,
+    #[serde(default)]
+    kdf: Kdf,
     #[serde(default)]
+    bound_values: bool,
+    /// HMAC over every other field, so entries cannot be dropped, swapped or
+    /// have their revision changed without the master key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct NameIndex {
+    tokens: HashMap<String, IndexEntry>,
     aliases: HashMap<String, String>,
+    /// Set in stores written with a MAC, so removing the MAC is not mistaken
+    /// for a store written before MACs existed.
+    #[serde(default)]
+    authenticated: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    id: String,
+    #[serde(flatten)]
+    metadata: TokenMetadata,
+}
+
+/// Layout version of `StoredDatabase`, written into every saved store. Bump it
+/// and add a step to `upgrade_schema` whenever the layout changes.
+const STORE_VERSION: u32 = 1;
+
+/// Encrypted into every saved store so a wrong master key is caught on open.
+const KEY_CHECK: &str = "srs-key-check";
+
+impl StoredDatabase {
+    /// The bytes the MAC covers: the store without its `mac` field, as JSON
+    /// with sorted keys so the encoding does not depend on map order.
+    fn mac_input(&self) -> Result<Vec<u8>> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("mac");
+        }
+        Ok(serde_json::to_vec(&value)?)
+    }
+
+    fn open(self, crypto_manager: &CryptoManager) -> Result<TokenDatabase> {
+        // Stores saved before the check existed fall back to their first value.
+        let check = self
+            .check
+            .as_ref()
+            .or_else(|| self.tokens.values().next().map(|entry| &entry.ciphertext))
+            .or_else(|| self.values.values().next());
+        if let Some(check) = check {
+            match crypto_manager.decrypt(check) {
+                Ok(marker)
+                    if self.check.is_none()
+                        || crypto::constant_time_eq(marker.as_bytes(), KEY_CHECK.as_bytes()) => {}
+                _ => return Err(StorageError::WrongMasterKey.into()),
+            }
+        }
+        if let Some(mac) = &self.mac {
+            if !crypto_manager.verify_mac(&self.mac_input()?, mac) {
+                return Err(StorageError::IntegrityCheckFailed.into());
+            }
+        }
+
+        let Some(index) = self.index else {
+            return Ok(TokenDatabase {
+                plaintext_names: !self.tokens.is_empty(),
+                unbound_values: !self.bound_values && !self.tokens.is_empty(),
+                tokens: self.tokens,
+                aliases: self.aliases,
+                revision: self.revision,
+                kdf: self.kdf,
+            });
+        };
+
+        let index = crypto_manager
+            .decrypt(&index)
+            .map_err(|_| anyhow::anyhow!("Store possibly corrupt, could not decrypt its index."))?;
+        let mut index: NameIndex = serde_json::from_str(&index).map_err(|e| {
+            anyhow::anyhow!("Store possibly corrupt, could not parse its index: {e}")
+        })?;
+        if index.authenticated && self.mac.is_none() {
+            return Err(StorageError::IntegrityCheckFailed.into());
+        }
+        let mut values = self.values;
+        let tokens: HashMap<String, TokenEntry> = index
+            .tokens
+            .drain()
+            .map(|(name, entry)| {
+                let ciphertext = values.remove(&entry.id).ok_or_else(|| {
+                    anyhow::anyhow!("Store possibly corrupt, the value of a token is missing.")
+                })?;
+                Ok((
+                    name,
+                    TokenEntry {
+                        ciphertext,
+                        metadata: entry.metadata,
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(TokenDatabase {
+            unbound_values: !self.bound_values && !tokens.is_empty(),
+            tokens,
+            aliases: index.aliases,
+            revision: self.revision,
+            kdf: self.kdf,
+            plaintext_names: false,
+        })
+    }
+}
+
+impl TokenDatabase {
+    fn seal(&self, crypto_manager: &CryptoManager) -> Result<StoredDatabase> {
+        let mut index = NameIndex {
+            aliases: self.aliases.clone(),
+            authenticated: true,
+            ..Default::default()
+        };
+        let mut values = HashMap::new();
+        for (id, (name, entry)) in self.tokens.iter().enumerate() {
+            let id = id.to_string();
+            values.insert(id.clone(), entry.ciphertext.clone());
+            index.tokens.insert(
+                name.clone(),
+                IndexEntry {
+                    id,
+                    metadata: entry.metadata.clone(),
+                },
+            );
+        }
+
+        let mut stored = StoredDatabase {
+            version: STORE_VERSION,
+            tokens: HashMap::new(),
+            aliases: HashMap::new(),
+            check: Some(crypto_manager.encrypt(KEY_CHECK)?),
+            index: Some(crypto_manager.encrypt(&serde_json::to_string(&index)?)?),
+            values,
+            revision: self.revision,
+            kdf: self.kdf.clone(),
+            bound_values: !self.unbound_values,
+            mac: None,
+        };
+        stored.mac = Some(crypto_manager.mac(&stored.mac_input()?));
+        Ok(stored)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TokenMetadata {
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Set for values stored as raw bytes, which need not be valid UTF-8.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub binary: bool,
+    /// Sorted, without duplicates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Variable the token is exported as instead of its name, used exactly
+    /// as given, without `--env-prefix` or `--upper`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_name: Option<String>,
+    /// Regex every new value must match, checked by `add` and `update`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+/// How long before a token's expiry `list` and `get` start warning about it.
+pub const EXPIRY_WARNING_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expiry {
+    Never,
+    Valid,
+    Soon { remaining: u64 },
+    Expired,
+}
+
+impl TokenMetadata {
+    pub(crate) fn new() -> Self {
+        let now = now();
+        Self {
+            created_at: now,
+            updated_at: now,
+            note: None,
+            expires_at: None,
+            binary: false,
+            tags: Vec::new(),
+            env_name: None,
+            pattern: None,
+        }
+    }
+
+    /// Whether the token carries every one of `tags`.
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.contains(tag))
+    }
+
+    pub fn expiry(&self, now: u64) -> Expiry {
+        match self.expires_at {
+            None => Expiry::Never,
+            Some(expires_at) if expires_at <= now => Expiry::Expired,
+            Some(expires_at) if expires_at - now <= EXPIRY_WARNING_SECS => Expiry::Soon {
+                remaining: expires_at - now,
+            },
+            Some(_) => Expiry::Valid,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "StoredToken")]
+struct TokenEntry {
+    ciphertext: String,
+    #[serde(flatten)]
+    metadata: TokenMetadata,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredToken {
+    Legacy(String),
+    Current {
+        ciphertext: String,
+        #[serde(flatten)]
+        metadata: TokenMetadata,
+    },
+}
+
+impl From<StoredToken> for TokenEntry {
+    fn from(stored: StoredToken) -> Self {
+        match stored {
+            StoredToken::Legacy(ciphertext) => TokenEntry {
+                ciphertext,
+                metadata: TokenMetadata::new(),
+            },
+            StoredToken::Current {
+                ciphertext,
+                metadata,
+            } => TokenEntry {
+                ciphertext,
+                metadata,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TokenInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub metadata: TokenMetadata,
+}
+
+/// Summary of a set of tokens for `list --stats`. Timestamps are seconds
+/// since the Unix epoch and `None` when there are no tokens.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct TokenStats {
+    pub total: usize,
+    pub expired: usize,
+    /// Not expired yet, but within [`EXPIRY_WARNING_SECS`] of it.
+    pub expiring_soon: usize,
+    pub by_tag: BTreeMap<String, usize>,
+    pub untagged: usize,
+    pub oldest_created: Option<u64>,
+    pub newest_created: Option<u64>,
+    pub last_modified: Option<u64>,
+}
+
+impl TokenStats {
+    pub fn collect(tokens: &[TokenInfo], now: u64) -> Self {
+        let mut stats = Self {
+            total: tokens.len(),
+            ..Default::default()
+        };
+        for token in tokens {
+            let metadata = &token.metadata;
+            match metadata.expiry(now) {
+                Expiry::Expired => stats.expired += 1,
+                Expiry::Soon { .. } => stats.expiring_soon += 1,
+                Expiry::Never | Expiry::Valid => {}
+            }
+            if metadata.tags.is_empty() {
+                stats.untagged += 1;
+            }
+            for tag in &metadata.tags {
+                *stats.by_tag.entry(tag.clone()).or_default() += 1;
+            }
+        }
+        let created = tokens.iter().map(|token| token.metadata.created_at);
+        stats.oldest_created = created.clone().min();
+        stats.newest_created = created.max();
+        stats.last_modified = tokens.iter().map(|token| token.metadata.updated_at).max();
+        stats
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenDump {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<TokenMetadata>,
+}
+
+impl TokenDump {
+    /// The value's bytes; binary values are carried as base64.
+    pub fn decoded_value(&self) -> Result<Zeroizing<Vec<u8>>> {
+        if self.metadata.as_ref().is_some_and(|m| m.binary) {
+            Ok(Zeroizing::new(
+                general_purpose::STANDARD
+                    .decode(&self.value)
+                    .map_err(|e| anyhow::anyhow!("the binary value is not valid base64: {e}"))?,
+            ))
+        } else {
+            Ok(Zeroizing::new(self.value.as_bytes().to_vec()))
+        }
+    }
+}
+
+/// How `import_encrypted` and `import_entries` resolve a token whose name is
+/// already taken.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum MergeStrategy {
+    Skip,
+    Overwrite,
+    Rename,
+}
+
+/// Order for `list`; the time-based orders put the oldest token first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum TokenSort {
+    #[default]
+    Name,
+    Created,
+    Updated,
+}
+
+/// The names an import stored, split the way the audit log records them.
+#[derive(Default)]
+struct StoredNames {
+    added: Vec<String>,
+    updated: Vec<String>,
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+    pub renamed: Vec<(String, String)>,
+    /// Entries that could not be stored, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+pub struct AddOptions {
+    pub overwrite: bool,
+    pub note: Option<String>,
+    pub expires_at: Option<u64>,
+    /// Replaces the token's tags when not empty.
+    pub tags: Vec<String>,
+    /// Replaces the variable the token is exported as when set.
+    pub env_name: Option<String>,
+    /// Replaces the pattern values must match when set. Without it, a
+    /// replaced token is still checked against the pattern it already has.
+    pub pattern: Option<String>,
+    /// Stores a value that is empty or only whitespace, which is otherwise
+    /// refused as most likely a slip at the prompt.
+    pub allow_empty: bool,
+}
+
+/// Changes to a token's metadata. `None` leaves a field as it is; for the
+/// note and expiry, `Some(None)` clears it, and empty `tags` clear the tags.
+#[derive(Default)]
+pub struct MetadataUpdate {
+    pub note: Option<Option<String>>,
+    pub tags: Option<Vec<String>>,
+    pub expires_at: Option<Option<u64>>,
+}
+
+#[derive(Default)]
+pub struct ChildEnvOptions {
+    pub env_file: Vec<(String, String)>,
+    pub env_file_wins: bool,
+    pub upper: bool,
+    pub inherit_only: Option<Vec<String>>,
+    pub only: Vec<String>,
+    /// Tokens and aliases left out; leaving out a token also leaves out the
+    /// aliases pointing at it.
+    pub exclude: Vec<String>,
+    /// Only tokens carrying all of these tags, and their aliases, are exported.
+    pub tags: Vec<String>,
+    pub env_prefix: String,
+    pub allow_protected: bool,
+}
+
+/// Variables that change how the spawned shell finds programs or loads code,
+/// so a token shadowing them could break or hijack the shell.
+const PROTECTED_VARS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "SHELL",
+    "USER",
+    "LOGNAME",
+    "PWD",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "ZDOTDIR",
+    "PROMPT_COMMAND",
+    "PS4",
+    "TERM",
+];
+const PROTECTED_PREFIXES: &[&str] = &["LD_", "DYLD_"];
+
+/// How many entries an import merges between two progress lines. Smaller
+/// imports finish too quickly to need any.
+const IMPORT_PROGRESS_EVERY: usize = 100;
+
+/// Fewest values worth handing to a decryption thread of their own.
+const PARALLEL_DECRYPT_MIN: usize = 32;
+
+/// How long [`TokenStorage::new`] waits for another srs process to release
+/// the store before failing with [`StorageError::Locked`].
+pub const LOCK_WAIT: Duration = Duration::from_secs(10);
+
+/// An encrypted token store backed by a single JSON file. Every mutating
+/// method writes the file before returning.
+pub struct TokenStorage {
+    file_path: PathBuf,
+    database: TokenDatabase,
+    crypto_manager: CryptoManager,
+    /// Where accesses are recorded; `None` while auditing is off.
+    audit_log: Option<PathBuf>,
+    /// Held from before the store is read until it is dropped, when opened
+    /// through [`TokenStorage::new`].
+    lock: Option<StoreLock>,
+    /// Changes stay in memory; see [`TokenStorage::set_dry_run`].
+    dry_run: bool,
+    /// Nothing is read from or written to disk; see [`TokenStorage::in_memory`].
+    in_memory: bool,
 }
 
-pub struct TokenStorage {
-    file_path: PathBuf,
-    database: TokenDatabase,
-    crypto_manager: CryptoManager,
-}
+impl TokenStorage {
+    /// Opens the store of `profile` (the configured default when `None`) in
+    /// the data directory set by the config file, reading the master key from
+    /// `key_source`. The store stays locked against other srs processes until
+    /// the returned storage is dropped.
+    pub fn new(key_source: &KeySource, profile: Option<&str>) -> Result<Self> {
+        Self::new_with_kdf(key_source, profile, Kdf::argon2id(), false)
+    }
+
+    /// Like [`TokenStorage::new`], creating the store with `kdf` if it does
+    /// not exist yet. Existing stores keep the KDF they were saved with. With
+    /// `dry_run` the store is opened as by [`TokenStorage::set_dry_run`], so
+    /// not even the upgrades of an old store format are saved.
+    pub fn new_with_kdf(
+        key_source: &KeySource,
+        profile: Option<&str>,
+        kdf: Kdf,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let config = Config::load()?;
+        let file_path = store_path(&config, profile)?;
+        let passphrase = Zeroizing::new(crypto::read_master_key(key_source)?);
+        // Locked after the prompt, so a slow typist does not hold up scripts.
+        let lock = StoreLock::acquire(&file_path, LOCK_WAIT)?;
+        let mut storage = Self::open_with(file_path, &passphrase, kdf, dry_run)?;
+        storage.lock = Some(lock);
+        storage.set_audit(config.audit);
+        Ok(storage)
+    }
+
+    /// Opens the store at `file_path`, deriving the key from `passphrase` with
+    /// the store's own key derivation settings. A missing file starts an empty
+    /// store, which is only written once a token is added.
+    ///
+    /// Fails with [`StorageError::WrongMasterKey`] if the passphrase does not
+    /// match the one the store was saved with.
+    pub fn open(file_path: impl Into<PathBuf>, passphrase: &str) -> Result<Self> {
+        Self::open_with_kdf(file_path, passphrase, Kdf::argon2id())
+    }
+
+    /// Like [`TokenStorage::open`], using `kdf` if the store is new.
+    pub fn open_with_kdf(
+        file_path: impl Into<PathBuf>,
+        passphrase: &str,
+        kdf: Kdf,
+    ) -> Result<Self> {
+        Self::open_with(file_path, passphrase, kdf, false)
+    }
+
+    fn open_with(
+        file_path: impl Into<PathBuf>,
+        passphrase: &str,
+        kdf: Kdf,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let file_path = file_path.into();
+        let stored = read_database(&file_path)?;
+        let kdf = stored.as_ref().map_or(kdf, |stored| stored.kdf.clone());
+        crate::debug!("::> Using store '{}'.", file_path.display());
+        crate::debug!("::> Deriving the key with {kdf}.");
+
+        let crypto_manager = CryptoManager::new(passphrase, &kdf)?;
+        let database = match stored {
+            Some(stored) => stored.open(&crypto_manager)?,
+            None => TokenDatabase {
+                kdf,
+                ..Default::default()
+            },
+        };
+        let mut storage = Self {
+            file_path,
+            database,
+            crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run,
+            in_memory: false,
+        };
+
+        if storage.migrate_legacy_kdf(passphrase)? {
+            crate::notice!("::> Upgraded store key derivation from SHA-256 to Argon2id.");
+        }
+        if storage.migrate_plaintext_names()? {
+            crate::notice!("::> Encrypted the token names in the store.");
+        }
+        if storage.migrate_unbound_values()? {
+            crate::notice!("::> Bound the stored values to their token names.");
+        }
+        Ok(storage)
+    }
+
+    /// Opens the store at `file_path` with an already derived key, for callers
+    /// that manage key material themselves. Without a passphrase the store
+    /// cannot be moved to a new key derivation, so it is opened as saved.
+    pub fn with_crypto_manager(
+        file_path: impl Into<PathBuf>,
+        crypto_manager: CryptoManager,
+    ) -> Result<Self> {
+        let file_path = file_path.into();
+        let database = match read_database(&file_path)? {
+            Some(stored) => stored.open(&crypto_manager)?,
+            None => TokenDatabase::default(),
+        };
+        let mut storage = Self {
+            file_path,
+            database,
+            crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run: false,
+            in_memory: false,
+        };
+        storage.migrate_plaintext_names()?;
+        storage.migrate_unbound_values()?;
+        Ok(storage)
+    }
+
+    /// An empty store that lives only in memory, for tests and for programs
+    /// embedding srs that keep the key material themselves. It behaves like
+    /// a store on disk, except that nothing is ever saved and there is no
+    /// audit log.
+    pub fn in_memory(crypto_manager: CryptoManager) -> Self {
+        Self {
+            file_path: PathBuf::new(),
+            database: TokenDatabase::default(),
+            crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run: false,
+            in_memory: true,
+        }
+    }
+
+    /// Turns the audit log next to the store on or off. It is off unless
+    /// enabled, and [`TokenStorage::new`] follows the `audit` config setting.
+    pub fn set_audit(&mut self, enabled: bool) {
+        self.audit_log = (enabled && !self.in_memory).then(|| audit::audit_path(&self.file_path));
+    }
+
+    /// Keeps every later change in memory: neither the store nor the audit
+    /// log is written, while methods still return what they would have done.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// The recorded events, oldest first. Readable even while auditing is off.
+    pub fn audit_events(&self) -> Result<Vec<AuditEvent>> {
+        if self.in_memory {
+            return Ok(Vec::new());
+        }
+        audit::read(&audit::audit_path(&self.file_path), &self.crypto_manager)
+    }
+
+    fn record<N: AsRef<str>>(&self, action: AuditAction, names: &[N]) -> Result<()> {
+        let Some(path) = self.audit_log.as_ref().filter(|_| !self.dry_run) else {
+            return Ok(());
+        };
+        let event = AuditEvent {
+            at: now(),
+            action,
+            names: names.iter().map(|name| name.as_ref().to_string()).collect(),
+        };
+        audit::append(path, &self.crypto_manager, &event)
+    }
+
+    #[cfg(test)]
+    fn load(&mut self) -> Result<()> {
+        if let Some(stored) = read_database(&self.file_path)? {
+            self.database = stored.open(&self.crypto_manager)?;
+        }
+        Ok(())
+    }
+
+    fn migrate_plaintext_names(&mut self) -> Result<bool> {
+        if !self.database.plaintext_names {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn migrate_unbound_values(&mut self) -> Result<bool> {
+        if !self.database.unbound_values {
+            return Ok(false);
+        }
+
+        let mut database = self.database.clone();
+        for (name, entry) in database.tokens.iter_mut() {
+            let token = Zeroizing::new(self.crypto_manager.decrypt_bytes(&entry.ciphertext)?);
+            entry.ciphertext = self
+                .crypto_manager
+                .encrypt_with_aad(&token, name.as_bytes())?;
+        }
+        database.unbound_values = false;
+
+        let snapshot = std::mem::replace(&mut self.database, database);
+        if let Err(error) = self.save() {
+            self.database = snapshot;
+            return Err(error);
+        }
+        Ok(true)
+    }
+
+    fn migrate_legacy_kdf(&mut self, passphrase: &str) -> Result<bool> {
+        if self.database.kdf != Kdf::LegacySha256 {
+            return Ok(false);
+        }
+
+        let mut decrypted_tokens = HashMap::new();
+        for (name, entry) in &self.database.tokens {
+            match self.decrypt_value(name, &entry.ciphertext) {
+                Ok(token) => decrypted_tokens.insert(name.clone(), Zeroizing::new(token)),
+                Err(_) => return Ok(false),
+            };
+        }
+
+        let kdf = Kdf::argon2id();
+        let crypto_manager = CryptoManager::new(passphrase, &kdf)?;
+        let mut tokens = std::mem::take(&mut self.database.tokens);
+        for (name, entry) in tokens.iter_mut() {
+            entry.ciphertext = crypto_manager
+                .encrypt_with_aad(&decrypted_tokens[name], self.database.value_aad(name))?;
+        }
+        self.database.tokens = tokens;
+
+        self.database.kdf = kdf;
+        self.crypto_manager = crypto_manager;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Fingerprint of the key the store is opened with, to check that two
+    /// copies of the store are unlocked with the same master key.
+    pub fn key_fingerprint(&self) -> String {
+        self.crypto_manager.fingerprint()
+    }
+
+    /// The key derivation the store is saved with.
+    pub fn kdf(&self) -> &Kdf {
+        &self.database.kdf
+    }
+
+    /// Re-encrypts every token under a new master key derived with `kdf`,
+    /// which should carry a fresh salt. The store, in memory and on disk, is
+    /// left as it was if any step fails.
+    pub fn rekey(&mut self, new_passphrase: &str, kdf: Kdf) -> Result<()> {
+        let crypto_manager = CryptoManager::new(new_passphrase, &kdf)?;
+        let audit_path = audit::audit_path(&self.file_path);
+        let events = self.audit_events()?;
+
+        let mut tokens = self.database.tokens.clone();
+        for (name, entry) in tokens.iter_mut() {
+            let token = Zeroizing::new(self.decrypt_value(name, &entry.ciphertext)?);
+            entry.ciphertext =
+                crypto_manager.encrypt_with_aad(&token, self.database.value_aad(name))?;
+        }
+
+        let snapshot = self.database.clone();
+        self.database.tokens = tokens;
+        self.database.kdf = kdf;
+        let previous_crypto_manager = std::mem::replace(&mut self.crypto_manager, crypto_manager);
+        if let Err(error) = self.save() {
+            self.database = snapshot;
+            self.crypto_manager = previous_crypto_manager;
+            return Err(error);
+        }
+        if !events.is_empty() && !self.dry_run {
+            audit::rewrite(&audit_path, &self.crypto_manager, &events)?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if self.dry_run || self.in_memory {
+            return Ok(());
+        }
+        self.database.revision += 1;
+        if let Err(error) = self.write() {
+            self.database.revision -= 1;
+            return Err(error);
+        }
+        self.database.plaintext_names = false;
+        Ok(())
+    }
+
+    fn write(&self) -> Result<()> {
+        write_store(&self.file_path, &self.encode()?)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let stored = self.database.seal(&self.crypto_manager)?;
+        Ok(serde_json::to_vec_pretty(&stored)?)
+    }
+
+    /// Returns the store exactly as it is on disk. Every write encrypts the
+    /// index under a fresh nonce, so re-encoding would never match the remote.
+    pub fn to_blob(&self) -> Result<Vec<u8>> {
+        if self.file_path.exists() {
+            Ok(fs::read(&self.file_path)?)
+        } else {
+            self.encode()
+        }
+    }
+
+    pub fn replace_from_blob(&mut self, blob: &[u8], key_source: &KeySource) -> Result<()> {
+        let stored = parse_database(blob)
+            .map_err(|e| anyhow::anyhow!("Remote store is not a valid srs store: {e}"))?;
+
+        let crypto_manager = if stored.kdf == self.database.kdf {
+            None
+        } else {
+            crate::notice!("::> Remote store uses a different key derivation salt.");
+            let passphrase = crypto::read_master_key(key_source)?;
+            Some(CryptoManager::new(&passphrase, &stored.kdf)?)
+        };
+        let crypto = crypto_manager.as_ref().unwrap_or(&self.crypto_manager);
+        let database = stored.open(crypto).map_err(|_| {
+            anyhow::anyhow!("Remote store was encrypted with a different master key.")
+        })?;
+
+        if !self.in_memory {
+            write_store(&self.file_path, blob)?;
+        }
+        self.database = database;
+        if let Some(crypto_manager) = crypto_manager {
+            self.crypto_manager = crypto_manager;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn store_token(&mut self, name: &str, token: &str) -> Result<()> {
+        self.store_many(&[(name, token)])
+    }
+
+    /// Stores every `(name, value)` pair, replacing existing tokens, and
+    /// writes the store once at the end instead of once per token.
+    pub fn store_many<N: AsRef<str>, V: AsRef<str>>(&mut self, entries: &[(N, V)]) -> Result<()> {
+        for (name, token) in entries {
+            self.insert_token(name.as_ref(), token.as_ref())?;
+        }
+        self.save()?;
+        let names = entries.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        self.record(AuditAction::Add, &names)
+    }
+
+    /// Decrypts the values of `names`, in order, spreading large batches over
+    /// a few threads since each AES-GCM decryption is independent. Fails as a
+    /// whole if any value fails to decrypt.
+    fn decrypt_many(&self, names: &[&String]) -> Result<Vec<Vec<u8>>> {
+        let decrypt = |names: &[&String]| {
+            names
+                .iter()
+                .map(|name| self.decrypt_value(name, &self.database.tokens[*name].ciphertext))
+                .collect::<Result<Vec<_>>>()
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = names.len().div_ceil(threads).max(PARALLEL_DECRYPT_MIN);
+        if names.len() <= chunk_size {
+            return decrypt(names);
+        }
+
+        std::thread::scope(|scope| {
+            let workers = names
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || decrypt(chunk)))
+                .collect::<Vec<_>>();
+            let mut values = Vec::with_capacity(names.len());
+            for worker in workers {
+                values.extend(worker.join().expect("decryption thread panicked")?);
+            }
+            Ok(values)
+        })
+    }
+
+    fn decrypt_value(&self, name: &str, ciphertext: &str) -> Result<Vec<u8>> {
+        self.crypto_manager
+            .decrypt_with_aad(ciphertext, self.database.value_aad(name))
+    }
+
+    fn insert_token(&mut self, name: &str, token: &str) -> Result<&mut TokenEntry> {
+        self.insert_value(name, token.as_bytes(), false)
+    }
+
+    fn insert_value(&mut self, name: &str, value: &[u8], binary: bool) -> Result<&mut TokenEntry> {
+        let ciphertext = self
+            .crypto_manager
+            .encrypt_with_aad(value, self.database.value_aad(name))?;
+        let entry = self
+            .database
+            .tokens
+            .entry(name.to_string())
+            .and_modify(|entry| entry.metadata.updated_at = now())
+            .or_insert_with(|| TokenEntry {
+                ciphertext: String::new(),
+                metadata: TokenMetadata::new(),
+            });
+        entry.ciphertext = ciphertext;
+        entry.metadata.binary = binary;
+        Ok(entry)
+    }
+
+    /// Encrypts and saves `token` under `name`. Fails with
+    /// [`StorageError::AlreadyExists`] if the name is taken, unless
+    /// `options.overwrite` is set.
+    pub fn add_token(&mut self, name: &str, token: &str, options: &AddOptions) -> Result<()> {
+        self.add_value(name, token.as_bytes(), false, options)
+    }
+
+    /// Like [`Self::add_token`] for arbitrary bytes, such as a key file. The
+    /// token is marked binary; read it back with [`Self::get_bytes`].
+    pub fn add_bytes(&mut self, name: &str, value: &[u8], options: &AddOptions) -> Result<()> {
+        self.add_value(name, value, true, options)
+    }
+
+    fn add_value(
+        &mut self,
+        name: &str,
+        value: &[u8],
+        binary: bool,
+        options: &AddOptions,
+    ) -> Result<()> {
+        if self.database.aliases.contains_key(name) {
+            return Err(anyhow::anyhow!("'{name}' already exists as an alias"));
+        }
+        if !options.overwrite && self.database.tokens.contains_key(name) {
+            return Err(StorageError::AlreadyExists(name.to_string()).into());
+        }
+        if is_blank(value, binary) && !options.allow_empty {
+            return Err(StorageError::EmptyValue(name.to_string()).into());
+        }
+
+        if let Some(var) = &options.env_name {
+            if env_var_name(var) != *var {
+                return Err(anyhow::anyhow!(
+                    "'{var}' is not a valid environment variable name"
+                ));
+            }
+        }
+
+        let pattern = options.pattern.as_ref().or_else(|| {
+            self.database
+                .tokens
+                .get(name)
+                .and_then(|entry| entry.metadata.pattern.as_ref())
+        });
+        if let Some(pattern) = pattern {
+            check_pattern(name, value, pattern)?;
+        }
+
+        let tags = normalize_tags(&options.tags)?;
+        let entry = self.insert_value(name, value, binary)?;
+        if !tags.is_empty() {
+            entry.metadata.tags = tags;
+        }
+        if options.env_name.is_some() {
+            entry.metadata.env_name = options.env_name.clone();
+        }
+        if options.pattern.is_some() {
+            entry.metadata.pattern = options.pattern.clone();
+        }
+        if options.note.is_some() {
+            entry.metadata.note = options.note.clone();
+        }
+        if options.expires_at.is_some() {
+            entry.metadata.expires_at = options.expires_at;
+        }
+        self.save()?;
+        self.record(AuditAction::Add, &[name])
+    }
+
+    /// Replaces the value of an existing token, following aliases, and keeps
+    /// its note, tags and expiry. The value must match `pattern`, which then
+    /// replaces the stored one, or else the token's stored pattern. Fails
+    /// with [`StorageError::NotFound`] if there is no such token and with
+    /// [`StorageError::EmptyValue`] if `token` is blank.
+    pub fn update_token(&mut self, name: &str, token: &str, pattern: Option<&str>) -> Result<()> {
+        let actual_name = self.resolve_alias(name).to_string();
+        let Some(entry) = self.database.tokens.get(&actual_name) else {
+            return Err(StorageError::NotFound(name.to_string()).into());
+        };
+        if is_blank(token.as_bytes(), false) {
+            return Err(StorageError::EmptyValue(name.to_string()).into());
+        }
+        if let Some(pattern) = pattern.or(entry.metadata.pattern.as_deref()) {
+            check_pattern(name, token.as_bytes(), pattern)?;
+        }
+        let entry = self.insert_token(&actual_name, token)?;
+        if let Some(pattern) = pattern {
+            entry.metadata.pattern = Some(pattern.to_string());
+        }
+        self.save()?;
+        self.record(AuditAction::Update, &[actual_name])
+    }
+
+    /// Changes the note, tags or expiry of an existing token, following
+    /// aliases. The value is not decrypted and its ciphertext is left as it
+    /// is, so `updated_at` still tells when the value last changed. Fails
+    /// with [`StorageError::NotFound`] if there is no such token.
+    pub fn update_metadata(&mut self, name: &str, update: &MetadataUpdate) -> Result<()> {
+        let actual_name = self.resolve_alias(name).to_string();
+        let tags = update.tags.as_deref().map(normalize_tags).transpose()?;
+        let Some(entry) = self.database.tokens.get_mut(&actual_name) else {
+            return Err(StorageError::NotFound(name.to_string()).into());
+        };
+        if let Some(note) = &update.note {
+            entry.metadata.note = note.clone();
+        }
+        if let Some(tags) = tags {
+            entry.metadata.tags = tags;
+        }
+        if let Some(expires_at) = update.expires_at {
+            entry.metadata.expires_at = expires_at;
+        }
+        self.save()?;
+        self.record(AuditAction::Update, &[actual_name])
+    }
+
+    /// Returns the metadata of a token, following aliases.
+    pub fn get_metadata(&self, name: &str) -> Option<&TokenMetadata> {
+        let actual_name = self.resolve_alias(name);
+        self.database
+            .tokens
+            .get(actual_name)
+            .map(|entry| &entry.metadata)
+    }
+
+    /// Decrypts a token, following aliases; `None` if there is no such name.
+    pub fn get_token(&self, name: &str) -> Result<Option<String>> {
+        let actual_name = self.resolve_alias(name);
+        match self.database.tokens.get(actual_name) {
+            Some(entry) if entry.metadata.binary => Err(anyhow::anyhow!(
+                "Token '{name}' holds binary data, read it with get --raw"
+            )),
+            Some(entry) => {
+                let decrypted_token = self.decrypt_value(actual_name, &entry.ciphertext)?;
+                self.record(AuditAction::Get, &[actual_name])?;
+                Ok(Some(crypto::utf8_text(decrypted_token)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypts a token's raw bytes, binary or text, following aliases.
+    pub fn get_bytes(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let actual_name = self.resolve_alias(name);
+        let Some(entry) = self.database.tokens.get(actual_name) else {
+            return Ok(None);
+        };
+        let value = self.decrypt_value(actual_name, &entry.ciphertext)?;
+        self.record(AuditAction::Get, &[actual_name])?;
+        Ok(Some(value))
+    }
+
+    /// Like [`Self::get_token`], replacing invalid UTF-8 instead of failing.
+    pub fn get_token_lossy(&self, name: &str) -> Result<Option<String>> {
+        let actual_name = self.resolve_alias(name);
+        match self.database.tokens.get(actual_name) {
+            Some(entry) => {
+                let decrypted_token =
+                    Zeroizing::new(self.decrypt_value(actual_name, &entry.ciphertext)?);
+                self.record(AuditAction::Get, &[actual_name])?;
+                Ok(Some(String::from_utf8_lossy(&decrypted_token).into_owned()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn merge_json_tokens(&self, names: &[String]) -> Result<Value> {
+        let mut merged = Map::new();
+        for name in names {
+            let token = self
+                .get_token(name)?
+                .ok_or_else(|| StorageError::NotFound(name.clone()))?;
+            match serde_json::from_str::<Value>(&token) {
+                Ok(Value::Object(object)) => merge_json_objects(&mut merged, object, "")?,
+                _ => return Err(anyhow::anyhow!("Token '{name}' is not a JSON object")),
+            }
+        }
+        Ok(Value::Object(merged))
+    }
+
+    fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        self.database
+            .aliases
+            .get(name)
+            .map_or(name, std::string::String::as_str)
+    }
+
+    /// Whether `name` is a token of its own; aliases do not count.
+    pub fn has_token(&self, name: &str) -> bool {
+        self.database.tokens.contains_key(name)
+    }
+
+    /// Lists the token names in no particular order; aliases are not included.
+    pub fn list_tokens(&self) -> Result<Vec<String>> {
+        self.ensure_not_empty()?;
+        Ok(self.database.tokens.keys().cloned().collect())
+    }
+
+    pub fn list_tokens_with_metadata(&self) -> Result<Vec<TokenInfo>> {
+        self.ensure_not_empty()?;
+        let mut tokens = self
+            .database
+            .tokens
+            .iter()
+            .map(|(name, entry)| TokenInfo {
+                name: name.clone(),
+                metadata: entry.metadata.clone(),
+            })
+            .collect::<Vec<_>>();
+        tokens.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tokens)
+    }
+
+    /// Lists the tokens whose names match `pattern`, sorted by name. A pattern
+    /// containing `*` or `?` is a glob over the whole name, anything else is a
+    /// substring; both ignore case.
+    pub fn list_tokens_matching(&self, pattern: &str) -> Result<Vec<TokenInfo>> {
+        let matcher = NameMatcher::new(pattern)?;
+        let mut tokens = self.list_tokens_with_metadata()?;
+        tokens.retain(|token| matcher.matches(&token.name));
+        Ok(tokens)
+    }
+
+    pub fn count_tokens(&self) -> Result<usize> {
+        Ok(self.database.tokens.len())
+    }
+
+    pub fn dump_tokens(&self) -> Result<Vec<TokenDump>> {
+        self.ensure_not_empty()?;
+
+        let mut dump = self
+            .database
+            .tokens
+            .iter()
+            .map(|(name, entry)| {
+                let value = self.decrypt_value(name, &entry.ciphertext)?;
+                // Binary values travel as base64, flagged by their metadata.
+                let value = if entry.metadata.binary {
+                    general_purpose::STANDARD.encode(Zeroizing::new(value))
+                } else {
+                    crypto::utf8_text(value)?
+                };
+                Ok(TokenDump {
+                    name: name.clone(),
+                    value,
+                    metadata: Some(entry.metadata.clone()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        dump.sort_by(|a, b| a.name.cmp(&b.name));
+        let names = dump.iter().map(|token| &token.name).collect::<Vec<_>>();
+        self.record(AuditAction::Get, &names)?;
+        Ok(dump)
+    }
+
+    pub fn import_tokens(
+        &mut self,
+        entries: &[TokenDump],
+        overwrite: bool,
+    ) -> Result<(usize, usize)> {
+        let strategy = if overwrite {
+            MergeStrategy::Overwrite
+        } else {
+            MergeStrategy::Skip
+        };
+        let snapshot = self.database.clone();
+        let (summary, stored) = self.merge_tokens(entries, strategy)?;
+        if let Some((name, reason)) = summary.failed.into_iter().next() {
+            self.database = snapshot;
+            return Err(anyhow::anyhow!("Could not import '{name}': {reason}"));
+        }
+        let imported = summary.added + summary.overwritten;
+        if imported > 0 {
+            self.save()?;
+            self.record_stored(&stored)?;
+        }
+        Ok((imported, summary.skipped))
+    }
+
+    /// Merges tokens read from another tool's export and saves once. An entry
+    /// that cannot be stored is listed in [`ImportSummary::failed`] and the
+    /// rest are still saved; the store is left as it was if saving fails.
+    pub fn import_entries(
+        &mut self,
+        entries: &[TokenDump],
+        strategy: MergeStrategy,
+    ) -> Result<ImportSummary> {
+        let snapshot = self.database.clone();
+        let result = self
+            .merge_tokens(entries, strategy)
+            .and_then(|(summary, stored)| {
+                self.save()?;
+                self.record_stored(&stored)?;
+                Ok(summary)
+            });
+        if result.is_err() {
+            self.database = snapshot;
+        }
+        result
+    }
+
+    fn merge_tokens(
+        &mut self,
+        entries: &[TokenDump],
+        strategy: MergeStrategy,
+    ) -> Result<(ImportSummary, StoredNames)> {
+        let mut summary = ImportSummary::default();
+        let mut stored = StoredNames::default();
+        for (index, entry) in entries.iter().enumerate() {
+            if entries.len() > IMPORT_PROGRESS_EVERY
+                && index > 0
+                && index % IMPORT_PROGRESS_EVERY == 0
+            {
+                crate::notice!("::> Imported {index}/{}", entries.len());
+            }
+            let exists = self.database.tokens.contains_key(&entry.name);
+            let name = match (exists, strategy) {
+                (true, MergeStrategy::Skip) => {
+                    summary.skipped += 1;
+                    continue;
+                }
+                (true, MergeStrategy::Rename) => self.free_import_name(&entry.name),
+                _ => entry.name.clone(),
+            };
+            if let Err(error) = self.merge_entry(&name, entry) {
+                summary
+                    .failed
+                    .push((entry.name.clone(), format!("{error:#}")));
+                continue;
+            }
+            match (exists, strategy) {
+                (true, MergeStrategy::Overwrite) => {
+                    summary.overwritten += 1;
+                    stored.updated.push(name);
+                }
+                (true, _) => {
+                    summary.renamed.push((entry.name.clone(), name.clone()));
+                    summary.added += 1;
+                    stored.added.push(name);
+                }
+                (false, _) => {
+                    summary.added += 1;
+                    stored.added.push(name);
+                }
+            }
+        }
+        Ok((summary, stored))
+    }
+
+    /// Records what an import stored, once it is saved.
+    fn record_stored(&self, stored: &StoredNames) -> Result<()> {
+        if !stored.added.is_empty() {
+            self.record(AuditAction::Add, &stored.added)?;
+        }
+        if !stored.updated.is_empty() {
+            self.record(AuditAction::Update, &stored.updated)?;
+        }
+        Ok(())
+    }
+
+    /// Stores one imported entry under `name`, checking it the way `add`
+    /// would before anything changes.
+    fn merge_entry(&mut self, name: &str, entry: &TokenDump) -> Result<()> {
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("the name is empty"));
+        }
+        if self.database.aliases.contains_key(name) {
+            return Err(anyhow::anyhow!("'{name}' already exists as an alias"));
+        }
+        let metadata = entry.metadata.as_ref();
+        if let Some(var) = metadata.and_then(|m| m.env_name.as_ref()) {
+            if env_var_name(var) != *var {
+                return Err(anyhow::anyhow!(
+                    "'{var}' is not a valid environment variable name"
+                ));
+            }
+        }
+        let value = entry.decoded_value()?;
+        if let Some(pattern) = metadata.and_then(|m| m.pattern.as_ref()) {
+            check_pattern(name, &value, pattern)?;
+        }
+
+        let binary = metadata.is_some_and(|m| m.binary);
+        let stored = self.insert_value(name, &value, binary)?;
+        let Some(metadata) = metadata else {
+            return Ok(());
+        };
+        stored.metadata.created_at = metadata.created_at;
+        if metadata.note.is_some() {
+            stored.metadata.note = metadata.note.clone();
+        }
+        if metadata.expires_at.is_some() {
+            stored.metadata.expires_at = metadata.expires_at;
+        }
+        if !metadata.tags.is_empty() {
+            stored.metadata.tags = metadata.tags.clone();
+        }
+        if metadata.env_name.is_some() {
+            stored.metadata.env_name = metadata.env_name.clone();
+        }
+        if metadata.pattern.is_some() {
+            stored.metadata.pattern = metadata.pattern.clone();
+        }
+        Ok(())
+    }
+
+    fn free_import_name(&self, name: &str) -> String {
+        let taken = |candidate: &String| {
+            self.database.tokens.contains_key(candidate)
+                || self.database.aliases.contains_key(candidate)
+        };
+        let mut candidate = format!("{name}_imported");
+        let mut suffix = 2;
+        while taken(&candidate) {
+            candidate = format!("{name}_imported_{suffix}");
+            suffix += 1;
+        }
+        candidate
+    }
+
+    pub fn export_encrypted(&self, path: &Path, password: &str) -> Result<usize> {
+        let backup = Backup {
+            tokens: self.dump_tokens()?,
+            aliases: self.database.aliases.clone(),
+        };
+        fs::write(path, seal::seal_backup(&backup, password)?)?;
+        Ok(backup.tokens.len())
+    }
+
+    /// Merges a backup written by `export_encrypted` into the store. The backup
+    /// is decrypted and authenticated before anything in the store changes, and
+    /// the store is left as it was if the merge fails part way.
+    pub fn import_encrypted(
+        &mut self,
+        path: &Path,
+        password: &str,
+        strategy: MergeStrategy,
+    ) -> Result<ImportSummary> {
+        let content = fs::read_to_string(path)?;
+        let backup = seal::unseal_backup(&content, password)?;
+
+        let snapshot = self.database.clone();
+        let result = self.merge_backup(backup, strategy);
+        if result.is_err() {
+            self.database = snapshot;
+        }
+        result
+    }
+
+    fn merge_backup(&mut self, backup: Backup, strategy: MergeStrategy) -> Result<ImportSummary> {
+        let (summary, stored) = self.merge_tokens(&backup.tokens, strategy)?;
+        // A backup is restored whole or not at all.
+        if let Some((name, reason)) = summary.failed.first() {
+            return Err(anyhow::anyhow!("Could not import '{name}': {reason}"));
+        }
+        let mut aliases = Vec::new();
+        for (alias, target) in backup.aliases {
+            // Aliases follow their target when it was imported under a new name.
+            let target = summary
+                .renamed
+                .iter()
+                .find(|(old, _)| *old == target)
+                .map_or(target, |(_, new)| new.clone());
+            if !self.database.tokens.contains_key(&alias)
+                && !self.database.aliases.contains_key(&alias)
+                && self.database.tokens.contains_key(&target)
+            {
+                self.database.aliases.insert(alias.clone(), target.clone());
+                aliases.push([alias, target]);
+            }
+        }
+        self.save()?;
+        self.record_stored(&stored)?;
+        for names in &aliases {
+            self.record(AuditAction::AddAlias, names)?;
+        }
+        Ok(summary)
+    }
+
+    pub fn find_nonce_collisions(&self) -> Result<Vec<Vec<String>>> {
+        let mut by_nonce: HashMap<[u8; 12], Vec<String>> = HashMap::new();
+        for (name, entry) in &self.database.tokens {
+            by_nonce
+                .entry(crypto::extract_nonce(&entry.ciphertext)?)
+                .or_default()
+                .push(name.clone());
+        }
+
+        let mut collisions = by_nonce
+            .into_values()
+            .filter(|names| names.len() > 1)
+            .map(|mut names| {
+                names.sort();
+                names
+            })
+            .collect::<Vec<_>>();
+        collisions.sort();
+        Ok(collisions)
+    }
+
+    fn ensure_not_empty(&self) -> Result<()> {
+        if self.database.tokens.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No tokens found, please add a token to start."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Deletes a token and the aliases pointing at it. Fails with
+    /// [`StorageError::NotFound`] if there is no such token.
+    pub fn delete_token(&mut self, name: &str) -> Result<()> {
+        self.delete_many(&[name])
+    }
+
+    /// Deletes every named token, and the aliases pointing at them, with a
+    /// single write. Nothing is deleted if any of the names is missing, which
+    /// includes every name of an empty store.
+    pub fn delete_many<N: AsRef<str>>(&mut self, names: &[N]) -> Result<()> {
+        if let Some(missing) = names
+            .iter()
+            .find(|name| !self.database.tokens.contains_key(name.as_ref()))
+        {
+            return Err(StorageError::NotFound(missing.as_ref().to_string()).into());
+        }
+        for name in names {
+            let name = name.as_ref();
+            self.database.tokens.remove(name);
+            self.database.aliases.retain(|_, target| target != name);
+        }
+        self.save()?;
+        self.record(AuditAction::Delete, names)
+    }
+
+    pub fn build_child_env(&self, options: &ChildEnvOptions) -> Result<HashMap<String, String>> {
+        Ok(self.child_env_with(self.token_env(options)?, options))
+    }
+
+    /// The child's environment around the already decrypted `token_env`.
+    fn child_env_with(
+        &self,
+        token_env: HashMap<String, String>,
+        options: &ChildEnvOptions,
+    ) -> HashMap<String, String> {
+        let mut child_env = match &options.inherit_only {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| std::env::var(name).ok().map(|value| (name.clone(), value)))
+                .collect::<HashMap<String, String>>(),
+            None => std::env::vars().collect::<HashMap<String, String>>(),
+        };
+        for (var, value) in &token_env {
+            if child_env.get(var).is_some_and(|current| current != value) {
+                eprintln!(
+                    "::> Warning: '{var}' replaces a variable of the same name in the current environment"
+                );
+            }
+        }
+
+        let env_file = options.env_file.iter().cloned();
+        if options.env_file_wins {
+            child_env.extend(token_env);
+            child_env.extend(env_file);
+        } else {
+            child_env.extend(env_file);
+            child_env.extend(token_env);
+        }
+        child_env
+    }
+
+    /// The variables `export-env` prints, recorded as a read of each of them.
+    pub fn export_env(&self, options: &ChildEnvOptions) -> Result<HashMap<String, String>> {
+        let token_env = self.token_env(options)?;
+        let mut exported = token_env.keys().collect::<Vec<_>>();
+        exported.sort();
+        self.record(AuditAction::Get, &exported)?;
+        Ok(token_env)
+    }
+
+    /// Decrypts the tokens and their aliases keyed by the variable names they
+    /// are exported under, honouring the naming and filtering options.
+    fn token_env(&self, options: &ChildEnvOptions) -> Result<HashMap<String, String>> {
+        let mut names = Vec::new();
+        for (name, entry) in &self.database.tokens {
+            if !entry.metadata.has_tags(&options.tags) {
+                continue;
+            }
+            if entry.metadata.binary {
+                if options.only.contains(name) {
+                    return Err(anyhow::anyhow!(
+                        "Token '{name}' holds binary data and cannot be exported"
+                    ));
+                }
+                crate::debug!("::> Not exporting '{name}', it holds binary data.");
+                continue;
+            }
+            names.push(name);
+        }
+        let mut token_env = HashMap::with_capacity(names.len());
+        for (name, value) in names.iter().zip(self.decrypt_many(&names)?) {
+            token_env.insert(name.to_string(), crypto::utf8_text(value)?);
+        }
+
+        for (alias, target) in &self.database.aliases {
+            if let Some(value) = token_env.get(target).cloned() {
+                token_env.insert(alias.clone(), value);
+            }
+        }
+
+        if !options.only.is_empty() {
+            if let Some(missing) = options.only.iter().find(|n| !token_env.contains_key(*n)) {
+                return Err(StorageError::NotFound(missing.clone()).into());
+            }
+            token_env.retain(|name, _| options.only.contains(name));
+        }
+        for name in &options.exclude {
+            if !self.database.tokens.contains_key(name) && !self.database.aliases.contains_key(name)
+            {
+                eprintln!("::> Warning: '{name}' is not a token or alias, nothing to exclude");
+            }
+        }
+        token_env.retain(|name, _| {
+            let target = self.resolve_alias(name);
+            !options
+                .exclude
+                .iter()
+                .any(|excluded| excluded == name || excluded == target)
+        });
+
+        // Tokens with an env_name claim their variable before names derived
+        // from token names, so an explicit mapping wins a collision.
+        let mut names = token_env
+            .keys()
+            .map(|name| {
+                let env_name = self
+                    .database
+                    .tokens
+                    .get(name)
+                    .and_then(|entry| entry.metadata.env_name.clone());
+                (env_name, name.clone())
+            })
+            .collect::<Vec<_>>();
+        names.sort_by(|(a_var, a), (b_var, b)| (a_var.is_none(), a).cmp(&(b_var.is_none(), b)));
+        let mut exported = HashMap::new();
+        for (env_name, name) in names {
+            let requested = env_name.unwrap_or_else(|| {
+                let requested = format!("{}{name}", options.env_prefix);
+                if options.upper {
+                    requested.to_uppercase()
+                } else {
+                    requested
+                }
+            });
+            let var = env_var_name(&requested);
+            if var != requested {
+                eprintln!(
+                    "::> Warning: '{requested}' is not a valid variable name, exporting it as '{var}'"
+                );
+            }
+            if !options.allow_protected && is_protected_var(&var) {
+                eprintln!(
+                    "::> Warning: not exporting '{var}', it would replace a protected variable (use --force to allow it)"
+                );
+                continue;
+            }
+            if exported.contains_key(&var) {
+                eprintln!(
+                    "::> Warning: skipping '{name}', another token is already exported as '{var}'"
+                );
+                continue;
+            }
+            exported.insert(var, token_env.remove(&name).unwrap_or_default());
+        }
+        Ok(exported)
+    }
+
+    /// Stores the value of `src` (or of the token it aliases) under the new
+    /// name `dst`, with fresh metadata. The value is decrypted and encrypted
+    /// again since it is bound to its name.
+    pub fn copy_token(&mut self, src: &str, dst: &str) -> Result<()> {
+        let source = self.resolve_alias(src);
+        let Some(entry) = self.database.tokens.get(source) else {
+            return Err(StorageError::NotFound(src.to_string()).into());
+        };
+        if self.database.aliases.contains_key(dst) {
+            return Err(anyhow::anyhow!("'{dst}' already exists as an alias"));
+        }
+        if self.database.tokens.contains_key(dst) {
+            return Err(StorageError::AlreadyExists(dst.to_string()).into());
+        }
+
+        let token = Zeroizing::new(self.decrypt_value(source, &entry.ciphertext)?);
+        let ciphertext = self
+            .crypto_manager
+            .encrypt_with_aad(&token, self.database.value_aad(dst))?;
+        let metadata = TokenMetadata {
+            binary: entry.metadata.binary,
+            ..TokenMetadata::new()
+        };
+        self.database.tokens.insert(
+            dst.to_string(),
+            TokenEntry {
+                ciphertext,
+                metadata,
+            },
+        );
+        self.save()?;
+        self.record(AuditAction::Add, &[dst])
+    }
+
+    /// Renames a token, keeping its value, metadata and aliases. Fails with
+    /// [`StorageError::NotFound`] or, unless `force` is set,
+    /// [`StorageError::AlreadyExists`].
+    pub fn rename_token(&mut self, old: &str, new: &str, force: bool) -> Result<()> {
+        self.ensure_not_empty()?;
+
+        if !self.database.tokens.contains_key(old) {
+            return Err(StorageError::NotFound(old.to_string()).into());
+        }
+        if self.database.aliases.contains_key(new) {
+            return Err(anyhow::anyhow!("'{new}' already exists as an alias"));
+        }
+        if !force && self.database.tokens.contains_key(new) {
+            return Err(StorageError::AlreadyExists(new.to_string()).into());
+        }
+
+        // The value is bound to its name, so it has to be encrypted again.
+        let token = Zeroizing::new(self.decrypt_value(old, &self.database.tokens[old].ciphertext)?);
+        let ciphertext = self
+            .crypto_manager
+            .encrypt_with_aad(&token, self.database.value_aad(new))?;
+        if let Some(mut entry) = self.database.tokens.remove(old) {
+            entry.ciphertext = ciphertext;
+            self.database.tokens.insert(new.to_string(), entry);
+        }
+        for target in self.database.aliases.values_mut() {
+            if target == old {
+                *target = new.to_string();
+            }
+        }
+        self.save()?;
+        self.record(AuditAction::Rename, &[old, new])
+    }
+
+    /// Spawns `shell`, or `$SHELL` when it is `None`, with the tokens loaded
+    /// and returns its exit status. With a `timeout` the shell is killed once
+    /// it has run that long, so the tokens do not linger in a forgotten one.
+    pub fn populate_tokens_to_child(
+        &mut self,
+        shell: Option<&Path>,
+        options: &ChildEnvOptions,
+        timeout: Option<Duration>,
+    ) -> Result<ExitStatus> {
+        let shell = resolve_shell(shell, std::env::var("SHELL").ok())?;
+        let program = shell.to_string_lossy();
+        let (status, timed_out) =
+            self.spawn_with_tokens(&program, &[], options, AuditAction::Shell, timeout)?;
+        if timed_out {
+            eprintln!(
+                "::> The shell was closed after {}s, start a new one with srs shell.",
+                timeout.map_or(0, |timeout| timeout.as_secs())
+            );
+        }
+        Ok(status)
+    }
+
+    /// Runs `program` directly (not through a shell) with the tokens in its
+    /// environment and waits for it to exit.
+    pub fn run_with_tokens(
+        &mut self,
+        program: &str,
+        args: &[String],
+        options: &ChildEnvOptions,
+    ) -> Result<ExitStatus> {
+        let (status, _) = self.spawn_with_tokens(program, args, options, AuditAction::Run, None)?;
+        Ok(status)
+    }
+
+    /// Returns the child's exit status and whether `timeout` ran out first.
+    fn spawn_with_tokens(
+        &mut self,
+        program: &str,
+        args: &[String],
+        options: &ChildEnvOptions,
+        action: AuditAction,
+        timeout: Option<Duration>,
+    ) -> Result<(ExitStatus, bool)> {
+        self.ensure_not_empty()?;
+
+        let token_env = self.token_env(options)?;
+        let mut exported = token_env.keys().cloned().collect::<Vec<_>>();
+        exported.sort();
+        let mut child_env = self.child_env_with(token_env, options);
+        let child = std::process::Command::new(program)
+            .args(args)
+            .env_clear()
+            .envs(&child_env)
+            .spawn();
+        // The child has its own copy now, so wipe ours instead of keeping
+        // the values around for as long as the child runs.
+        for value in child_env.values_mut() {
+            value.zeroize();
+        }
+
+        let mut child = child.map_err(|e| anyhow::anyhow!("Could not run '{program}': {e}"))?;
+        if action == AuditAction::Shell {
+            crate::info!(
+                "::> Spawned a new shell with {} token(s) loaded.",
+                exported.len()
+            );
+        }
+        self.record(action, &exported)?;
+        // Nothing is saved from here on, so let other srs processes in
+        // while the child runs, which may be for a whole shell session.
+        self.lock = None;
+        wait_with_timeout(&mut child, timeout)
+    }
+
+    /// Makes `alias` resolve to the existing token `target`.
+    pub fn add_alias(&mut self, alias: &str, target: &str) -> Result<()> {
+        self.ensure_not_empty()?;
+
+        if !self.database.tokens.contains_key(target) {
+            return Err(anyhow::anyhow!("Target token '{target}' does not exist"));
+        }
+
+        if self.database.tokens.contains_key(alias) {
+            return Err(anyhow::anyhow!("'{alias}' already exists as a token name"));
+        }
+
+        if self.database.aliases.contains_key(alias) {
+            return Err(anyhow::anyhow!("Alias '{alias}' already exists"));
+        }
+
+        self.database
+            .aliases
+            .insert(alias.to_string(), target.to_string());
+        self.save()?;
+        self.record(AuditAction::AddAlias, &[alias, target])
+    }
+
+    /// Removes an alias, returning whether it existed.
+    pub fn remove_alias(&mut self, alias: &str) -> Result<bool> {
+        self.ensure_not_empty()?;
+
+        let Some(target) = self.database.aliases.remove(alias) else {
+            return Ok(false);
+        };
+        self.save()?;
+        self.record(AuditAction::RemoveAlias, &[alias, &target])?;
+        Ok(true)
+    }
+
+    /// Lists `(alias, target)` pairs in no particular order.
+    pub fn list_aliases(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .database
+            .aliases
+            .iter()
+            .map(|(alias, target)| (alias.clone(), target.clone()))
+            .collect())
+    }
+}
+
+/// Picks the shell to spawn: an explicit one, which must be an executable
+/// file (bare names are looked up in `PATH`), then `env_shell`, then the
+/// platform default.
+pub fn resolve_shell(explicit: Option<&Path>, env_shell: Option<String>) -> Result<PathBuf> {
+    if let Some(shell) = explicit {
+        let path = if shell.components().count() == 1 && !shell.is_absolute() {
+            find_in_path(shell).unwrap_or_else(|| shell.to_path_buf())
+        } else {
+            shell.to_path_buf()
+        };
+        if !is_executable(&path) {
+            return Err(anyhow::anyhow!(
+                "Shell '{}' does not exist or is not executable",
+                shell.display()
+            ));
+        }
+        return Ok(path);
+    }
+    match env_shell {
+        Some(shell) if !shell.is_empty() => Ok(PathBuf::from(shell)),
+        _ => Ok(default_shell()),
+    }
+}
+
+#[cfg(windows)]
+fn default_shell() -> PathBuf {
+    std::env::var_os("COMSPEC")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("cmd.exe"))
+}
+
+#[cfg(not(windows))]
+fn default_shell() -> PathBuf {
+    PathBuf::from("/bin/sh")
+}
+
+fn find_in_path(name: &Path) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Whether `value` is empty or, unless it is binary, only whitespace.
+pub fn is_blank(value: &[u8], binary: bool) -> bool {
+    if binary {
+        value.is_empty()
+    } else {
+        value.iter().all(u8::is_ascii_whitespace)
+    }
+}
+
+/// Fails unless `value` is text matching `pattern`. The value itself is never
+/// part of the error.
+fn check_pattern(name: &str, value: &[u8], pattern: &str) -> Result<()> {
+    let regex =
+        Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid pattern '{pattern}': {e}"))?;
+    let matches = std::str::from_utf8(value).is_ok_and(|text| regex.is_match(text));
+    if !matches {
+        return Err(anyhow::anyhow!(
+            "Value for '{name}' does not match the pattern '{pattern}'"
+        ));
+    }
+    Ok(())
+}
+
+/// Sorts and deduplicates tags, rejecting empty ones and ones that would be
+/// awkward on the command line.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>> {
+    let mut normalized = Vec::new();
+    for tag in tags {
+        if tag.is_empty()
+            || !tag
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid tag '{tag}', use letters, digits, '-', '_', '.' and ':' only"
+            ));
+        }
+        normalized.push(tag.clone());
+    }
+    normalized.sort();
+    normalized.dedup();
+    Ok(normalized)
+}
+
+/// Each profile is a separate store file with its own master key; the default
+/// profile keeps using `srs.json` so existing stores are picked up unchanged.
+fn profile_path(data_dir: &Path, profile: Option<&str>) -> Result<PathBuf> {
+    let name = match profile {
+        None | Some(DEFAULT_PROFILE) => return Ok(data_dir.join(STORE_FILE)),
+        Some(name) => name,
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid profile name '{name}', use letters, digits, '-' and '_' only"
+        ));
+    }
+
+    let profiles_dir = data_dir.join("profiles");
+    fs::create_dir_all(&profiles_dir)?;
+    Ok(profiles_dir.join(format!("{name}.json")))
+}
+
+/// The store file `TokenStorage::new` opens for `profile` under `config`.
+pub fn store_path(config: &Config, profile: Option<&str>) -> Result<PathBuf> {
+    let data_dir = config.data_dir();
+    fs::create_dir_all(&data_dir)?;
+    profile_path(&data_dir, config.profile(profile))
+}
+
+pub fn list_profiles(config: &Config) -> Result<Vec<String>> {
+    profiles_in(&config.data_dir())
+}
+
+fn profiles_in(data_dir: &Path) -> Result<Vec<String>> {
+    let mut profiles = Vec::new();
+    if data_dir.join(STORE_FILE).exists() {
+        profiles.push(DEFAULT_PROFILE.to_string());
+    }
+
+    let profiles_dir = data_dir.join("profiles");
+    if profiles_dir.is_dir() {
+        let mut named = Vec::new();
+        for entry in fs::read_dir(profiles_dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    named.push(stem.to_string());
+                }
+            }
+        }
+        named.sort();
+        profiles.extend(named);
+    }
+    Ok(profiles)
+}
+
+/// Filters token names for `list`, ignoring case.
+pub struct NameMatcher(Regex);
+
+impl NameMatcher {
+    /// A pattern with `*` or `?` is a glob that must match the whole name;
+    /// anything else matches names that contain it.
+    pub fn new(pattern: &str) -> Result<Self> {
+        let expression = if pattern.contains(['*', '?']) {
+            let glob = pattern
+                .chars()
+                .map(|c| match c {
+                    '*' => ".*".to_string(),
+                    '?' => ".".to_string(),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect::<String>();
+            format!("(?i)^{glob}$")
+        } else {
+            format!("(?i){}", regex::escape(pattern))
+        };
+        Ok(Self(Regex::new(&expression)?))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+pub fn sort_tokens(tokens: &mut [TokenInfo], sort: TokenSort) {
+    match sort {
+        TokenSort::Name => tokens.sort_by(|a, b| a.name.cmp(&b.name)),
+        TokenSort::Created => tokens.sort_by_key(|token| token.metadata.created_at),
+        TokenSort::Updated => tokens.sort_by_key(|token| token.metadata.updated_at),
+    }
+}
+
+/// Turns `name` into a portable environment variable identifier by replacing
+/// anything outside `[A-Za-z0-9_]` and avoiding a leading digit.
+fn env_var_name(name: &str) -> String {
+    let mut var = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if var.is_empty() || var.starts_with(|c: char| c.is_ascii_digit()) {
+        var.insert(0, '_');
+    }
+    var
+}
+
+fn is_protected_var(var: &str) -> bool {
+    PROTECTED_VARS.contains(&var)
+        || PROTECTED_PREFIXES
+            .iter()
+            .any(|prefix| var.starts_with(prefix))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Replaces the store file without ever leaving a partial one behind: the new
+/// contents go to a staging file next to it, are read back and parsed, and only
+/// then renamed over the old file.
+fn write_store(path: &Path, content: &[u8]) -> Result<()> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".tmp");
+    let staging = PathBuf::from(staging);
+
+    let result = stage_store(&staging, content).and_then(|()| Ok(fs::rename(&staging, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
+
+fn stage_store(staging: &Path, content: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    // Only the owner can read the store, whatever the umask. The values are
+    // encrypted, but the file still reveals how many there are.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(staging)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content)?;
+    file.sync_all()?;
+    parse_database(&fs::read(staging)?)
+        .map_err(|e| anyhow::anyhow!("Refusing to save a store that does not parse back: {e}"))?;
+    Ok(())
+}
+
+/// Waits for `child`, killing it once `timeout` has passed. Returns its exit
+/// status and whether it had to be killed.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Option<Duration>,
+) -> Result<(ExitStatus, bool)> {
+    let Some(timeout) = timeout else {
+        return Ok((child.wait()?, false));
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            // Fails only if the child exited just now, which wait() reports.
+            let _ = child.kill();
+            return Ok((child.wait()?, true));
+        }
+        std::thread::sleep(left.min(Duration::from_millis(100)));
+    }
+}
+
+/// An advisory lock on `<store>.lock`. Without it two processes could both
+/// read the store, change it and save, and the second save would silently
+/// drop the first change. The lock is released when this is dropped, or by
+/// the OS if the process dies.
+pub(crate) struct StoreLock {
+    _file: fs::File,
+}
+
+impl StoreLock {
+    /// Locks the store at `store`, retrying for up to `wait` while another
+    /// process holds it.
+    pub(crate) fn acquire(store: &Path, wait: Duration) -> Result<Self> {
+        if let Some(parent) = store.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let path = store.with_extension("lock");
+        let mut options = fs::OpenOptions::new();
+        options.create(true).truncate(false).write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&path).map_err(|e| {
+            anyhow::anyhow!("Could not open the lock file '{}': {e}", path.display())
+        })?;
+
+        let deadline = Instant::now() + wait;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(fs::TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(fs::TryLockError::WouldBlock) => return Err(StorageError::Locked.into()),
+                Err(fs::TryLockError::Error(e)) => {
+                    return Err(anyhow::anyhow!("Could not lock '{}': {e}", path.display()))
+                }
+            }
+        }
+    }
+}
+
+/// What can be learned about a store file without its master key.
+pub(crate) struct StoreHeader {
+    pub revision: u64,
+    pub kdf: Kdf,
+    pub values: usize,
+}
+
+/// Parses a store file's contents without decrypting or changing anything.
+pub(crate) fn read_store_header(content: &[u8]) -> Result<StoreHeader> {
+    let stored = parse_database(content)?;
+    Ok(StoreHeader {
+        revision: stored.revision,
+        values: stored.values.len().max(stored.tokens.len()),
+        kdf: stored.kdf,
+    })
+}
+
+/// Decrypts a store's contents in memory and returns how many tokens and
+/// aliases it holds. Unlike [`TokenStorage::open`] no migration is run and
+/// nothing is written.
+pub(crate) fn count_entries(content: &[u8], passphrase: &str) -> Result<(usize, usize)> {
+    let stored = parse_database(content)?;
+    let crypto_manager = CryptoManager::new(passphrase, &stored.kdf)?;
+    let database = stored.open(&crypto_manager)?;
+    Ok((database.tokens.len(), database.aliases.len()))
+}
+
+/// Decrypts every value of a store's contents one by one, in memory, and
+/// reports for each token, by name, whether it decrypted. The key is only
+/// read once the contents are known to parse; nothing is written.
+pub fn verify_values(
+    content: &[u8],
+    read_key: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Result<Vec<(String, Result<()>)>> {
+    let stored = parse_database(content)?;
+    let crypto_manager = CryptoManager::new(&read_key()?, &stored.kdf)?;
+    let database = stored.open(&crypto_manager)?;
+    let mut results = database
+        .tokens
+        .iter()
+        .map(|(name, entry)| {
+            let result = crypto_manager
+                .decrypt_with_aad(&entry.ciphertext, database.value_aad(name))
+                .map(drop)
+                .map_err(|_| anyhow::anyhow!("the value does not decrypt"));
+            (name.clone(), result)
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+/// Reads the store at `path`; `None` only if there is no such file. Any other
+/// failure is an error, since treating an unreadable store as empty would
+/// hide its tokens and let the next save replace them.
+fn read_database(path: &Path) -> Result<Option<StoredDatabase>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "Could not read the store '{}': {e}",
+                path.display()
+            ))
+        }
+    };
+    Ok(Some(parse_database(&content)?))
+}
+
+/// Parses a store of any version up to `STORE_VERSION`, upgrading older
+/// layouts first. Stores from a newer srs are refused before their contents
+/// are interpreted.
+fn parse_database(content: &[u8]) -> Result<StoredDatabase> {
+    let corrupt = |e| anyhow::anyhow!("Store possibly corrupt, could not parse it: {e}");
+    let value = upgrade_schema(serde_json::from_slice(content).map_err(corrupt)?)?;
+    serde_json::from_value(value).map_err(corrupt)
+}
+
+fn upgrade_schema(mut value: Value) -> Result<Value> {
+    let Some(object) = value.as_object_mut() else {
+        return Err(anyhow::anyhow!(
+            "Store possibly corrupt, could not parse it: expected a JSON object"
+        ));
+    };
+    let version = object.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > u64::from(STORE_VERSION) {
+        return Err(anyhow::anyhow!(
+            "Store created by a newer srs (format version {version}), please upgrade srs to open it."
+        ));
+    }
+
+    if version == 0 {
+        // Unversioned stores: every layout written before the version field
+        // is still read through the serde defaults, so only the version is
+        // stamped. Key derivation, name encryption and value binding are
+        // upgraded after the store is decrypted.
+        object.insert("version".to_string(), STORE_VERSION.into());
+    }
+    Ok(value)
+}
+
+fn merge_json_objects(
+    base: &mut Map<String, Value>,
+    overlay: Map<String, Value>,
+    path: &str,
+) -> Result<()> {
+    for (key, value) in overlay {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(incoming)) => {
+                merge_json_objects(existing, incoming, &key_path)?;
+            }
+            (Some(Value::Object(_)), _) | (Some(_), Value::Object(_)) => {
+                return Err(anyhow::anyhow!(
+                    "Cannot merge '{key_path}': an object conflicts with a non-object value"
+                ));
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn setup_storage() -> TokenStorage {
+        let temp_path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        if temp_path.exists() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        let key = [0u8; 32];
+        let crypto_manager = CryptoManager::from_key(key);
+
+        let mut storage = TokenStorage {
+            file_path: temp_path,
+            database: TokenDatabase::default(),
+            crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run: false,
+            in_memory: false,
+        };
+
+        storage.load().unwrap();
+        storage
+    }
+
+    #[test]
+    fn store_and_get_token() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+
+        let token = storage.get_token("foo").unwrap();
+        assert_eq!(token.unwrap(), "bar");
+    }
+
+    #[test]
+    fn add_token_refuses_to_overwrite_by_default() {
+        let mut storage = setup_storage();
+        storage
+            .add_token("foo", "bar", &AddOptions::default())
+            .unwrap();
+
+        let result = storage.add_token("foo", "baz", &AddOptions::default());
+        assert_eq!(
+            result.unwrap_err().downcast_ref(),
+            Some(&StorageError::AlreadyExists("foo".to_string()))
+        );
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+
+        storage
+            .add_token(
+                "foo",
+                "baz",
+                &AddOptions {
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "baz");
+    }
+
+    #[test]
+    fn add_token_refuses_empty_and_whitespace_values() {
+        let mut storage = setup_storage();
+        for value in ["", "   ", "\n", " \t\r\n"] {
+            let error = storage
+                .add_token("foo", value, &AddOptions::default())
+                .unwrap_err();
+            assert_eq!(
+                error.downcast_ref(),
+                Some(&StorageError::EmptyValue("foo".to_string()))
+            );
+        }
+        let error = storage
+            .add_bytes("blob", &[], &AddOptions::default())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to store an empty value for 'blob'"
+        );
+        assert!(!storage.file_path.exists());
+    }
+
+    #[test]
+    fn add_token_stores_empty_values_when_allowed() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            allow_empty: true,
+            ..Default::default()
+        };
+        storage.add_token("empty", "", &options).unwrap();
+        storage.add_token("blank", "  ", &options).unwrap();
+        assert_eq!(storage.get_token("empty").unwrap().unwrap(), "");
+        assert_eq!(storage.get_token("blank").unwrap().unwrap(), "  ");
+        // Surrounding whitespace is kept as it is, only blank values are refused.
+        storage
+            .add_token("padded", " x ", &AddOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn update_token_refuses_blank_values() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        let error = storage.update_token("foo", " \t\n", None).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::EmptyValue("foo".to_string()))
+        );
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn add_token_checks_the_value_against_a_pattern() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            pattern: Some("^ghp_[A-Za-z0-9]+$".to_string()),
+            ..Default::default()
+        };
+        storage.add_token("github", "ghp_abc123", &options).unwrap();
+        assert_eq!(
+            storage.get_metadata("github").unwrap().pattern.as_deref(),
+            Some("^ghp_[A-Za-z0-9]+$")
+        );
+
+        let error = storage
+            .add_token("other", "not a token", &options)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Value for 'other' does not match the pattern '^ghp_[A-Za-z0-9]+$'"
+        );
+        assert!(storage.get_token("other").unwrap().is_none());
+
+        let invalid = AddOptions {
+            pattern: Some("ghp_(".to_string()),
+            ..Default::default()
+        };
+        let error = storage.add_token("x", "ghp_", &invalid).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid pattern 'ghp_(':"));
+    }
+
+    #[test]
+    fn update_token_rechecks_the_stored_pattern() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            pattern: Some("^[0-9]{4}$".to_string()),
+            ..Default::default()
+        };
+        storage.add_token("pin", "1234", &options).unwrap();
+
+        assert!(storage.update_token("pin", "12ab", None).is_err());
+        assert_eq!(storage.get_token("pin").unwrap().unwrap(), "1234");
+        storage.update_token("pin", "5678", None).unwrap();
+
+        // A new pattern replaces the stored one once the value matches it.
+        storage
+            .update_token("pin", "123456", Some("^[0-9]{6}$"))
+            .unwrap();
+        assert!(storage.update_token("pin", "9999", None).is_err());
+        assert_eq!(
+            storage.get_metadata("pin").unwrap().pattern.as_deref(),
+            Some("^[0-9]{6}$")
+        );
+    }
+
+    #[test]
+    fn update_metadata_leaves_the_value_untouched() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("old".to_string()),
+            tags: vec!["work".to_string()],
+            expires_at: Some(100),
+            ..Default::default()
+        };
+        storage.add_token("github", "ghp_abc", &options).unwrap();
+        storage.add_alias("GH", "github").unwrap();
+        let ciphertext = storage.database.tokens["github"].ciphertext.clone();
+        let updated_at = storage.get_metadata("github").unwrap().updated_at;
+
+        let update = MetadataUpdate {
+            note: Some(Some("CI deploy key".to_string())),
+            tags: Some(vec!["ci".to_string(), "aws".to_string()]),
+            expires_at: Some(Some(200)),
+        };
+        storage.update_metadata("GH", &update).unwrap();
+
+        let metadata = storage.get_metadata("github").unwrap();
+        assert_eq!(metadata.note.as_deref(), Some("CI deploy key"));
+        assert_eq!(metadata.tags, ["aws", "ci"]);
+        assert_eq!(metadata.expires_at, Some(200));
+        assert_eq!(metadata.updated_at, updated_at);
+        assert_eq!(storage.database.tokens["github"].ciphertext, ciphertext);
+        assert_eq!(storage.get_token("github").unwrap().unwrap(), "ghp_abc");
+
+        let error = storage
+            .update_metadata("missing", &MetadataUpdate::default())
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::NotFound("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn update_metadata_clears_fields() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("note".to_string()),
+            tags: vec!["work".to_string()],
+            expires_at: Some(100),
+            ..Default::default()
+        };
+        storage.add_token("github", "ghp_abc", &options).unwrap();
+
+        // Fields that are not mentioned keep their values.
+        let clear_note = MetadataUpdate {
+            note: Some(None),
+            ..Default::default()
+        };
+        storage.update_metadata("github", &clear_note).unwrap();
+        let metadata = storage.get_metadata("github").unwrap();
+        assert_eq!(metadata.note, None);
+        assert_eq!(metadata.tags, ["work"]);
+        assert_eq!(metadata.expires_at, Some(100));
+
+        let clear_rest = MetadataUpdate {
+            tags: Some(Vec::new()),
+            expires_at: Some(None),
+            ..Default::default()
+        };
+        storage.update_metadata("github", &clear_rest).unwrap();
+        let metadata = storage.get_metadata("github").unwrap();
+        assert!(metadata.tags.is_empty());
+        assert_eq!(metadata.expires_at, None);
+    }
+
+    #[test]
+    fn add_token_refuses_alias_names() {
+        let mut storage = setup_storage();
+        storage
+            .add_token("foo", "bar", &AddOptions::default())
+            .unwrap();
+        storage.add_alias("alias", "foo").unwrap();
+
+        assert!(storage
+            .add_token(
+                "alias",
+                "baz",
+                &AddOptions {
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn generated_token_is_retrievable() {
+        use crate::generate::{generate, Charset};
+
+        let mut storage = setup_storage();
+        let token = generate(24, Charset::Hex);
+        storage
+            .add_token("generated", &token, &AddOptions::default())
+            .unwrap();
+        assert_eq!(storage.get_token("generated").unwrap().unwrap(), token);
+    }
+
+    #[test]
+    fn get_nonexistent_token() {
+        let storage = setup_storage();
+        let token = storage.get_token("nonexistent").unwrap();
+        assert!(token.is_none());
+    }
+
+    #[test]
+    fn delete_token() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.delete_token("foo").unwrap();
+
+        let token = storage.get_token("foo").unwrap();
+        assert!(token.is_none());
+    }
+
+    #[test]
+    fn delete_nonexistent_token() {
+        let mut storage = setup_storage();
+        let error = storage.delete_token("nonexistent").unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::NotFound("nonexistent".to_string()))
+        );
+
+        storage.store_token("foo", "bar").unwrap();
+        let error = storage.delete_token("nonexistent").unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::NotFound("nonexistent".to_string()))
+        );
+    }
+
+    #[test]
+    fn list_tokens() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+
+        let tokens = storage.list_tokens().unwrap();
+        assert!(tokens.contains(&"foo".to_string()));
+        assert!(tokens.contains(&"baz".to_string()));
+    }
+
+    #[test]
+    fn count_tokens() {
+        let mut storage = setup_storage();
+        assert_eq!(storage.count_tokens().unwrap(), 0);
+
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+        storage.add_alias("alias", "foo").unwrap();
+        assert_eq!(storage.count_tokens().unwrap(), 2);
+    }
+
+    #[test]
+    fn dump_tokens_includes_values() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+
+        let dump = storage.dump_tokens().unwrap();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0].name, "baz");
+        assert_eq!(dump[0].value, "qux");
+        assert_eq!(dump[1].name, "foo");
+        assert_eq!(dump[1].value, "bar");
+    }
+
+    #[test]
+    fn get_token_lossy_recovers_invalid_utf8() {
+        let mut storage = setup_storage();
+        let encrypted = storage
+            .crypto_manager
+            .encrypt_with_aad(b"abc\xfe", b"broken")
+            .unwrap();
+        storage.database.tokens.insert(
+            "broken".to_string(),
+            TokenEntry {
+                ciphertext: encrypted,
+                metadata: TokenMetadata::new(),
+            },
+        );
+
+        assert!(storage.get_token("broken").is_err());
+        assert_eq!(
+            storage.get_token_lossy("broken").unwrap().unwrap(),
+            "abc\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn merge_json_tokens_later_keys_win() {
+        let mut storage = setup_storage();
+        storage
+            .store_token(
+                "base",
+                r#"{"host":"localhost","db":{"user":"dev","port":5432}}"#,
+            )
+            .unwrap();
+        storage
+            .store_token(
+                "prod",
+                r#"{"host":"prod.example.com","db":{"user":"admin"}}"#,
+            )
+            .unwrap();
+
+        let merged = storage
+            .merge_json_tokens(&["base".to_string(), "prod".to_string()])
+            .unwrap();
+        assert_eq!(
+            merged,
+            serde_json::json!({
+                "host": "prod.example.com",
+                "db": {"user": "admin", "port": 5432}
+            })
+        );
+    }
+
+    #[test]
+    fn merge_json_tokens_rejects_non_objects() {
+        let mut storage = setup_storage();
+        storage.store_token("obj", r#"{"a":1}"#).unwrap();
+        storage.store_token("arr", "[1,2,3]").unwrap();
+        storage.store_token("text", "plain").unwrap();
+
+        assert!(storage
+            .merge_json_tokens(&["obj".to_string(), "arr".to_string()])
+            .is_err());
+        assert!(storage
+            .merge_json_tokens(&["obj".to_string(), "text".to_string()])
+            .is_err());
+        assert!(storage
+            .merge_json_tokens(&["obj".to_string(), "missing".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn merge_json_tokens_rejects_structural_conflicts() {
+        let mut storage = setup_storage();
+        storage
+            .store_token("a", r#"{"db":{"user":"dev"}}"#)
+            .unwrap();
+        storage.store_token("b", r#"{"db":"postgres://"}"#).unwrap();
+
+        let result = storage.merge_json_tokens(&["a".to_string(), "b".to_string()]);
+        assert!(result.unwrap_err().to_string().contains("db"));
+    }
+
+    #[test]
+    fn build_child_env_merges_env_file() {
+        let mut storage = setup_storage();
+        storage
+            .store_token("SRS_TEST_SHARED", "from_token")
+            .unwrap();
+        storage
+            .add_alias("SRS_TEST_ALIAS", "SRS_TEST_SHARED")
+            .unwrap();
+
+        let options = ChildEnvOptions {
+            env_file: vec![
+                ("SRS_TEST_SHARED".to_string(), "from_file".to_string()),
+                ("SRS_TEST_PROJECT".to_string(), "project".to_string()),
+            ],
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env["SRS_TEST_SHARED"], "from_token");
+        assert_eq!(child_env["SRS_TEST_ALIAS"], "from_token");
+        assert_eq!(child_env["SRS_TEST_PROJECT"], "project");
+
+        let options = ChildEnvOptions {
+            env_file_wins: true,
+            ..options
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env["SRS_TEST_SHARED"], "from_file");
+        assert_eq!(child_env["SRS_TEST_ALIAS"], "from_token");
+    }
+
+    #[test]
+    fn import_tokens_skips_existing_unless_overwrite() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "old").unwrap();
+
+        let entries = vec![
+            TokenDump {
+                name: "foo".to_string(),
+                value: "new".to_string(),
+                metadata: None,
+            },
+            TokenDump {
+                name: "bar".to_string(),
+                value: "baz".to_string(),
+                metadata: None,
+            },
+        ];
+
+        assert_eq!(storage.import_tokens(&entries, false).unwrap(), (1, 1));
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "old");
+        assert_eq!(storage.get_token("bar").unwrap().unwrap(), "baz");
+
+        assert_eq!(storage.import_tokens(&entries, true).unwrap(), (2, 0));
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "new");
+    }
+
+    #[test]
+    fn build_child_env_uppercases_names() {
+        let mut storage = setup_storage();
+        storage.store_token("srs_test_lower", "value").unwrap();
+        storage
+            .add_alias("srs_test_lower_alias", "srs_test_lower")
+            .unwrap();
+
+        let options = ChildEnvOptions {
+            upper: true,
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env["SRS_TEST_LOWER"], "value");
+        assert_eq!(child_env["SRS_TEST_LOWER_ALIAS"], "value");
+        assert!(!child_env.contains_key("srs_test_lower"));
+    }
+
+    #[test]
+    fn build_child_env_applies_prefix() {
+        let mut storage = setup_storage();
+        storage.store_token("github", "value").unwrap();
+
+        let options = ChildEnvOptions {
+            env_prefix: "srs_".to_string(),
+            upper: true,
+            inherit_only: Some(Vec::new()),
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env.len(), 1);
+        assert_eq!(child_env["SRS_GITHUB"], "value");
+    }
+
+    #[test]
+    fn build_child_env_sanitizes_invalid_names() {
+        let mut storage = setup_storage();
+        storage.store_token("my-token", "dash").unwrap();
+        storage.store_token("my.token", "dot").unwrap();
+        storage.store_token("1password", "digit").unwrap();
+
+        let options = ChildEnvOptions {
+            inherit_only: Some(Vec::new()),
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env.len(), 2);
+        assert_eq!(child_env["_1password"], "digit");
+        // "my-token" sorts first, so it wins the clash with "my.token".
+        assert_eq!(child_env["my_token"], "dash");
+    }
+
+    #[test]
+    fn build_child_env_skips_protected_names() {
+        let mut storage = setup_storage();
+        storage.store_token("PATH", "/tmp/evil").unwrap();
+        storage.store_token("LD_PRELOAD", "/tmp/evil.so").unwrap();
+        storage.store_token("TOKEN_A", "a").unwrap();
+
+        let options = ChildEnvOptions {
+            inherit_only: Some(Vec::new()),
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert!(!child_env.contains_key("PATH"));
+        assert!(!child_env.contains_key("LD_PRELOAD"));
+        assert_eq!(child_env["TOKEN_A"], "a");
+
+        let options = ChildEnvOptions {
+            inherit_only: Some(Vec::new()),
+            allow_protected: true,
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env["PATH"], "/tmp/evil");
+    }
+
+    #[test]
+    fn build_child_env_keeps_inherited_path() {
+        let mut storage = setup_storage();
+        storage.store_token("PATH", "/tmp/evil").unwrap();
+
+        let options = ChildEnvOptions {
+            inherit_only: Some(vec!["PATH".to_string()]),
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert_eq!(child_env.get("PATH"), std::env::var("PATH").ok().as_ref());
+    }
+
+    #[test]
+    fn env_var_name_keeps_valid_names() {
+        assert_eq!(env_var_name("GITHUB_TOKEN"), "GITHUB_TOKEN");
+        assert_eq!(env_var_name("a b"), "a_b");
+        assert_eq!(env_var_name(""), "_");
+    }
+
+    #[test]
+    fn build_child_env_inherits_only_named_vars_and_tokens() {
+        let mut storage = setup_storage();
+        storage.store_token("TOKEN_A", "a").unwrap();
+        storage.store_token("TOKEN_B", "b").unwrap();
+        storage.add_alias("ALIAS_B", "TOKEN_B").unwrap();
+
+        let options = ChildEnvOptions {
+            inherit_only: Some(vec!["PATH".to_string()]),
+            only: vec!["TOKEN_A".to_string(), "ALIAS_B".to_string()],
+            ..Default::default()
+        };
+        let child_env = storage.build_child_env(&options).unwrap();
+
+        let mut keys = child_env.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["ALIAS_B", "PATH", "TOKEN_A"]);
+        assert_eq!(child_env["ALIAS_B"], "b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_with_tokens_injects_tokens_and_returns_status() {
+        let mut storage = setup_storage();
+        storage.store_token("SRS_RUN_A", "a").unwrap();
+        storage.store_token("SRS_RUN_B", "b").unwrap();
+
+        let script = r#"test "$SRS_RUN_A" = a && test -z "$SRS_RUN_B" && exit 7"#;
+        let options = ChildEnvOptions {
+            only: vec!["SRS_RUN_A".to_string()],
+            ..Default::default()
+        };
+        let status = storage
+            .run_with_tokens("sh", &["-c".to_string(), script.to_string()], &options)
+            .unwrap();
+        assert_eq!(status.code(), Some(7));
+    }
+
+    #[test]
+    fn wait_with_timeout_kills_a_child_that_runs_too_long() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        let (status, timed_out) =
+            wait_with_timeout(&mut child, Some(Duration::from_millis(200))).unwrap();
+        assert!(timed_out);
+        assert!(!status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn wait_with_timeout_returns_the_status_of_a_child_that_exits_in_time() {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "exit 3"])
+            .spawn()
+            .unwrap();
+        let (status, timed_out) =
+            wait_with_timeout(&mut child, Some(Duration::from_secs(10))).unwrap();
+        assert!(!timed_out);
+        assert_eq!(status.code(), Some(3));
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        assert!(!wait_with_timeout(&mut child, None).unwrap().1);
+    }
+
+    #[test]
+    fn run_with_tokens_reports_missing_program() {
+        let mut storage = setup_storage();
+        storage.store_token("SRS_RUN_A", "a").unwrap();
+
+        let error = storage
+            .run_with_tokens("srs-test-no-such-program", &[], &ChildEnvOptions::default())
+            .unwrap_err();
+        assert!(error.to_string().contains("srs-test-no-such-program"));
+    }
+
+    #[test]
+    fn build_child_env_rejects_unknown_only_names() {
+        let mut storage = setup_storage();
+        storage.store_token("TOKEN_A", "a").unwrap();
+
+        let options = ChildEnvOptions {
+            only: vec!["MISSING".to_string()],
+            ..Default::default()
+        };
+        assert!(storage.build_child_env(&options).is_err());
+    }
+
+    #[test]
+    fn find_nonce_collisions() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+        assert!(storage.find_nonce_collisions().unwrap().is_empty());
+
+        let reused = storage.database.tokens["foo"].clone();
+        storage.database.tokens.insert("copy".to_string(), reused);
+        assert_eq!(
+            storage.find_nonce_collisions().unwrap(),
+            vec![vec!["copy".to_string(), "foo".to_string()]]
+        );
+    }
+
+    #[test]
+    fn save_increments_revision() {
+        let mut storage = setup_storage();
+        assert_eq!(storage.database.revision, 0);
+
+        storage.store_token("foo", "bar").unwrap();
+        storage.add_alias("alias", "foo").unwrap();
+        assert_eq!(storage.database.revision, 2);
+    }
+
+    #[test]
+    fn store_many_saves_once() {
+        let mut storage = setup_storage();
+        let entries = (0..10)
+            .map(|i| (format!("token_{i}"), format!("value_{i}")))
+            .collect::<Vec<_>>();
+
+        storage.store_many(&entries).unwrap();
+        assert_eq!(storage.database.revision, 1);
+        assert_eq!(storage.get_token("token_7").unwrap().unwrap(), "value_7");
+
+        let reopened =
+            TokenStorage::with_crypto_manager(&storage.file_path, CryptoManager::from_key([0; 32]))
+                .unwrap();
+        assert_eq!(reopened.list_tokens().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn delete_many_saves_once_and_is_all_or_nothing() {
+        let mut storage = setup_storage();
+        storage
+            .store_many(&[("a", "1"), ("b", "2"), ("c", "3")])
+            .unwrap();
+        storage.add_alias("alias_b", "b").unwrap();
+        let revision = storage.database.revision;
+
+        let error = storage.delete_many(&["a", "missing"]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(StorageError::NotFound(name)) if name == "missing"
+        ));
+        assert!(storage.get_token("a").unwrap().is_some());
+        assert_eq!(storage.database.revision, revision);
+
+        storage.delete_many(&["a", "b"]).unwrap();
+        assert_eq!(storage.database.revision, revision + 1);
+        assert_eq!(storage.list_tokens().unwrap(), ["c"]);
+        assert!(storage.list_aliases().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replace_from_blob_keeps_remote_revision() {
+        let mut remote = setup_storage();
+        remote.store_token("foo", "bar").unwrap();
+        remote.store_token("baz", "qux").unwrap();
+        let blob = remote.to_blob().unwrap();
+
+        let mut local = setup_storage();
+        local.store_token("old", "value").unwrap();
+        local.replace_from_blob(&blob, &KeySource::Prompt).unwrap();
+
+        assert_eq!(local.database.revision, 2);
+        assert_eq!(local.get_token("foo").unwrap().unwrap(), "bar");
+        assert!(local.get_token("old").unwrap().is_none());
+    }
+
+    #[test]
+    fn replace_from_blob_rejects_foreign_master_key() {
+        let mut remote = setup_storage();
+        remote.crypto_manager = CryptoManager::from_key([1u8; 32]);
+        remote.store_token("foo", "bar").unwrap();
+        let blob = remote.to_blob().unwrap();
+
+        let mut local = setup_storage();
+        assert!(local.replace_from_blob(&blob, &KeySource::Prompt).is_err());
+        assert!(local
+            .replace_from_blob(b"not json", &KeySource::Prompt)
+            .is_err());
+    }
+
+    #[test]
+    fn migrate_legacy_kdf_rekeys_tokens() {
+        let mut storage = setup_storage();
+        storage.crypto_manager = CryptoManager::new("passphrase", &Kdf::LegacySha256).unwrap();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+
+        assert!(storage.migrate_legacy_kdf("passphrase").unwrap());
+        assert!(matches!(storage.database.kdf, Kdf::Argon2id { .. }));
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+
+        let mut reopened = setup_storage();
+        reopened.file_path = storage.file_path.clone();
+        reopened.crypto_manager = CryptoManager::new("passphrase", &storage.database.kdf).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.get_token("baz").unwrap().unwrap(), "qux");
+        assert!(!reopened.migrate_legacy_kdf("passphrase").unwrap());
+    }
+
+    #[test]
+    fn migrate_legacy_kdf_skips_wrong_passphrase() {
+        let mut storage = setup_storage();
+        storage.crypto_manager = CryptoManager::new("passphrase", &Kdf::LegacySha256).unwrap();
+        storage.store_token("foo", "bar").unwrap();
+        let original = storage.database.tokens["foo"].ciphertext.clone();
+
+        storage.crypto_manager = CryptoManager::new("wrong", &Kdf::LegacySha256).unwrap();
+        assert!(!storage.migrate_legacy_kdf("wrong").unwrap());
+        assert_eq!(storage.database.kdf, Kdf::LegacySha256);
+        assert_eq!(storage.database.tokens["foo"].ciphertext, original);
+    }
+
+    #[test]
+    fn rekey_reencrypts_under_the_new_key_only() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+        storage.add_alias("alias", "foo").unwrap();
+
+        storage.rekey("new passphrase", Kdf::argon2id()).unwrap();
+        assert!(matches!(storage.database.kdf, Kdf::Argon2id { .. }));
+        assert!(CryptoManager::from_key([0u8; 32])
+            .decrypt(&storage.database.tokens["foo"].ciphertext)
+            .is_err());
+
+        let mut reopened = setup_storage();
+        reopened.file_path = storage.file_path.clone();
+        reopened.crypto_manager =
+            CryptoManager::new("new passphrase", &storage.database.kdf).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.get_token("alias").unwrap().unwrap(), "bar");
+        assert_eq!(reopened.get_token("baz").unwrap().unwrap(), "qux");
+
+        reopened.crypto_manager = CryptoManager::from_key([0u8; 32]);
+        assert!(reopened.load().is_err());
+    }
+
+    #[test]
+    fn child_env_holds_every_token_of_a_large_store() {
+        let mut storage = setup_storage();
+        let entries = (0..500)
+            .map(|i| (format!("TOKEN_{i}"), format!("value_{i}")))
+            .collect::<Vec<_>>();
+        storage.store_many(&entries).unwrap();
+
+        let child_env = storage
+            .build_child_env(&ChildEnvOptions {
+                inherit_only: Some(Vec::new()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(child_env.len(), entries.len());
+        for (name, value) in &entries {
+            assert_eq!(child_env.get(name), Some(value));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_value_that_fails_to_decrypt_stops_the_spawn() {
+        let mut storage = setup_storage();
+        let entries = (0..200)
+            .map(|i| (format!("TOKEN_{i}"), format!("value_{i}")))
+            .collect::<Vec<_>>();
+        storage.store_many(&entries).unwrap();
+        let other = storage.database.tokens["TOKEN_1"].ciphertext.clone();
+        storage
+            .database
+            .tokens
+            .get_mut("TOKEN_150")
+            .unwrap()
+            .ciphertext = other;
+
+        assert!(storage.token_env(&ChildEnvOptions::default()).is_err());
+        let marker = std::env::temp_dir().join(format!("srs_spawned_{}", Uuid::new_v4()));
+        let touch = vec!["-c".to_string(), format!("touch '{}'", marker.display())];
+        assert!(storage
+            .run_with_tokens("sh", &touch, &ChildEnvOptions::default())
+            .is_err());
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn update_token_requires_an_existing_token() {
+        let mut storage = setup_storage();
+        let error = storage.update_token("missing", "value", None).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::NotFound("missing".to_string()))
+        );
+        assert!(storage.get_metadata("missing").is_none());
+    }
+
+    #[test]
+    fn update_token_replaces_the_value_and_keeps_metadata() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("CI deploy key".to_string()),
+            tags: vec!["work".to_string()],
+            ..Default::default()
+        };
+        storage.add_token("deploy", "old", &options).unwrap();
+        storage.add_alias("DEPLOY", "deploy").unwrap();
+
+        storage.update_token("DEPLOY", "new", None).unwrap();
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("deploy").unwrap().unwrap(), "new");
+        let metadata = storage.get_metadata("deploy").unwrap();
+        assert_eq!(metadata.note.as_deref(), Some("CI deploy key"));
+        assert_eq!(metadata.tags, ["work"]);
+        assert!(storage.get_metadata("DEPLOY").is_some());
+    }
+
+    #[test]
+    fn add_existing_without_overwrite_errors() {
+        let mut storage = setup_storage();
+        storage
+            .add_token("deploy", "old", &AddOptions::default())
+            .unwrap();
+        let error = storage
+            .add_token("deploy", "new", &AddOptions::default())
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::AlreadyExists("deploy".to_string()))
+        );
+        assert_eq!(storage.get_token("deploy").unwrap().unwrap(), "old");
+    }
+
+    #[test]
+    fn stats_summarize_tokens_with_varied_metadata() {
+        let info = |name: &str, created_at, updated_at, expires_at, tags: &[&str]| TokenInfo {
+            name: name.to_string(),
+            metadata: TokenMetadata {
+                created_at,
+                updated_at,
+                expires_at,
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                ..TokenMetadata::new()
+            },
+        };
+        let now = 1_000_000;
+        let tokens = [
+            info("aws", 100, 900, Some(now - 1), &["work", "aws"]),
+            info("github", 50, 500, Some(now + 60), &["work"]),
+            info("npm", 300, 300, Some(now + EXPIRY_WARNING_SECS * 2), &[]),
+            info("wifi", 200, 200_000, None, &[]),
+        ];
+
+        let stats = TokenStats::collect(&tokens, now);
+        assert_eq!(
+            stats,
+            TokenStats {
+                total: 4,
+                expired: 1,
+                expiring_soon: 1,
+                by_tag: BTreeMap::from([("aws".to_string(), 1), ("work".to_string(), 2)]),
+                untagged: 2,
+                oldest_created: Some(50),
+                newest_created: Some(300),
+                last_modified: Some(200_000),
+            }
+        );
+        assert_eq!(TokenStats::collect(&[], now), TokenStats::default());
+    }
+
+    fn audit_trail(storage: &TokenStorage) -> Vec<(AuditAction, Vec<String>)> {
+        storage
+            .audit_events()
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.action, event.names))
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn operations_are_recorded_in_the_audit_log() {
+        let mut storage = setup_storage();
+        storage.set_audit(true);
+        storage
+            .add_token("github", "ghp_audit_secret", &AddOptions::default())
+            .unwrap();
+        storage.add_alias("GH", "github").unwrap();
+        storage.get_token("GH").unwrap();
+        storage.get_token("missing").unwrap();
+        storage.rename_token("github", "gh_token", false).unwrap();
+        storage.get_bytes("gh_token").unwrap();
+        #[cfg(unix)]
+        storage
+            .run_with_tokens("true", &[], &ChildEnvOptions::default())
+            .unwrap();
+        storage.delete_token("gh_token").unwrap();
+
+        let mut expected = vec![
+            (AuditAction::Add, names(&["github"])),
+            (AuditAction::AddAlias, names(&["GH", "github"])),
+            (AuditAction::Get, names(&["github"])),
+            (AuditAction::Rename, names(&["github", "gh_token"])),
+            (AuditAction::Get, names(&["gh_token"])),
+        ];
+        #[cfg(unix)]
+        expected.push((AuditAction::Run, names(&["GH", "gh_token"])));
+        expected.push((AuditAction::Delete, names(&["gh_token"])));
+        assert_eq!(audit_trail(&storage), expected);
+
+        let log = fs::read(audit::audit_path(&storage.file_path)).unwrap();
+        let log = String::from_utf8_lossy(&log);
+        assert!(!log.contains("ghp_audit_secret"));
+        assert!(!log.contains("github"));
+        for event in storage.audit_events().unwrap() {
+            let json = serde_json::to_string(&event).unwrap();
+            assert!(!json.contains("ghp_audit_secret"));
+        }
+        let _ = fs::remove_file(audit::audit_path(&storage.file_path));
+    }
+
+    #[test]
+    fn exports_are_recorded_as_reads() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+        storage.set_audit(true);
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        storage.export_encrypted(&backup_path, "hunter2").unwrap();
+        storage.export_env(&ChildEnvOptions::default()).unwrap();
+
+        assert_eq!(
+            audit_trail(&storage),
+            [
+                (AuditAction::Get, names(&["baz", "foo"])),
+                (AuditAction::Get, names(&["baz", "foo"])),
+            ]
+        );
+        let _ = fs::remove_file(backup_path);
+        let _ = fs::remove_file(audit::audit_path(&storage.file_path));
+    }
+
+    #[test]
+    fn imports_and_alias_changes_are_recorded() {
+        let mut source = setup_storage();
+        source.store_token("foo", "bar").unwrap();
+        source.store_token("baz", "qux").unwrap();
+        source.add_alias("f", "foo").unwrap();
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        source.export_encrypted(&backup_path, "hunter2").unwrap();
+
+        let mut target = setup_storage();
+        target.store_token("baz", "old").unwrap();
+        target.set_audit(true);
+        target
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Overwrite)
+            .unwrap();
+        let batch = [TokenDump {
+            name: "new".to_string(),
+            value: "value".to_string(),
+            metadata: None,
+        }];
+        target.import_tokens(&batch, false).unwrap();
+        target.remove_alias("f").unwrap();
+
+        assert_eq!(
+            audit_trail(&target),
+            [
+                (AuditAction::Add, names(&["foo"])),
+                (AuditAction::Update, names(&["baz"])),
+                (AuditAction::AddAlias, names(&["f", "foo"])),
+                (AuditAction::Add, names(&["new"])),
+                (AuditAction::RemoveAlias, names(&["f", "foo"])),
+            ]
+        );
+        let _ = fs::remove_file(backup_path);
+        let _ = fs::remove_file(audit::audit_path(&target.file_path));
+    }
+
+    #[test]
+    fn nothing_is_recorded_while_auditing_is_off() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.set_audit(true);
+        storage.get_token("foo").unwrap();
+        storage.set_audit(false);
+        storage.get_token("foo").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+
+        assert_eq!(audit_trail(&storage), [(AuditAction::Get, names(&["foo"]))]);
+        let _ = fs::remove_file(audit::audit_path(&storage.file_path));
+    }
+
+    #[test]
+    fn rekey_keeps_the_audit_log_readable() {
+        let mut storage = setup_storage();
+        storage.set_audit(true);
+        storage.store_token("foo", "bar").unwrap();
+        storage.rekey("new passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage.get_token("foo").unwrap();
+
+        assert_eq!(
+            audit_trail(&storage),
+            [
+                (AuditAction::Add, names(&["foo"])),
+                (AuditAction::Get, names(&["foo"]))
+            ]
+        );
+        let _ = fs::remove_file(audit::audit_path(&storage.file_path));
+    }
+
+    #[test]
+    fn dry_run_reports_changes_without_saving_them() {
+        let mut storage = setup_storage();
+        storage.set_audit(true);
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
+        let backup_path = export_sample_backup();
+        let store = fs::read(&storage.file_path).unwrap();
+        let audit_log = fs::read(audit::audit_path(&storage.file_path)).unwrap();
+
+        storage.set_dry_run(true);
+        storage
+            .add_token("new", "value", &AddOptions::default())
+            .unwrap();
+        storage.delete_token("baz").unwrap();
+        storage.rename_token("foo", "renamed", false).unwrap();
+        let summary = storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Overwrite)
+            .unwrap();
+        storage.rekey("new passphrase", Kdf::pbkdf2(1_000)).unwrap();
+
+        // The would-be result is visible in memory...
+        assert_eq!((summary.added, summary.overwritten), (2, 0));
+        let mut names = storage.list_tokens().unwrap();
+        names.sort();
+        assert_eq!(names, ["bar", "foo", "new", "renamed"]);
+        assert!(storage.get_token("baz").unwrap().is_none());
+        // ...but neither the store nor the audit log was touched.
+        assert_eq!(fs::read(&storage.file_path).unwrap(), store);
+        assert_eq!(
+            fs::read(audit::audit_path(&storage.file_path)).unwrap(),
+            audit_log
+        );
+        let reopened = TokenStorage::with_crypto_manager(
+            &storage.file_path,
+            CryptoManager::from_key([0u8; 32]),
+        )
+        .unwrap();
+        let mut names = reopened.list_tokens().unwrap();
+        names.sort();
+        assert_eq!(names, ["baz", "foo"]);
+        let _ = fs::remove_file(audit::audit_path(&storage.file_path));
+        let _ = fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn new_store_keeps_the_requested_kdf() {
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        let mut storage =
+            TokenStorage::open_with_kdf(&path, "passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage.store_token("foo", "bar").unwrap();
+
+        // The stored header wins over the KDF asked for on later opens.
+        let reopened = TokenStorage::open(&path, "passphrase").unwrap();
+        assert!(matches!(
+            reopened.kdf(),
+            Kdf::Pbkdf2Sha256 {
+                iterations: 1_000,
+                ..
+            }
+        ));
+        assert_eq!(reopened.get_token("foo").unwrap().unwrap(), "bar");
+        let error = TokenStorage::open(&path, "wrong").err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
+
+        let mut reopened = reopened;
+        reopened.rekey("passphrase", Kdf::argon2id()).unwrap();
+        let switched = TokenStorage::open(&path, "passphrase").unwrap();
+        assert!(matches!(switched.kdf(), Kdf::Argon2id { .. }));
+        assert_eq!(switched.get_token("foo").unwrap().unwrap(), "bar");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn rekey_failure_leaves_store_intact() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        let revision = storage.database.revision;
+        let kdf = storage.database.kdf.clone();
+
+        let file_path = storage.file_path.clone();
+        storage.file_path = std::env::temp_dir()
+            .join(format!("srs_missing_{}", Uuid::new_v4()))
+            .join("srs.json");
+        assert!(storage.rekey("new passphrase", Kdf::argon2id()).is_err());
+        assert_eq!(storage.database.revision, revision);
+        assert_eq!(storage.database.kdf, kdf);
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+
+        storage.file_path = file_path;
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn failed_save_keeps_the_previous_store() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        let revision = storage.database.revision;
+
+        let mut staging = storage.file_path.clone().into_os_string();
+        staging.push(".tmp");
+        std::fs::create_dir(&staging).unwrap();
+        assert!(storage.store_token("foo", "baz").is_err());
+        assert_eq!(storage.database.revision, revision);
+        std::fs::remove_dir(&staging).unwrap();
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn store_file_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        // A staging file left behind by a crash must not pass on its mode.
+        let staging = path.with_extension("json.tmp");
+        fs::write(&staging, "").unwrap();
+        fs::set_permissions(&staging, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut storage =
+            TokenStorage::open_with_kdf(&path, "passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage.store_token("foo", "bar").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let reopened = TokenStorage::open(&path, "passphrase").unwrap();
+        assert_eq!(reopened.get_token("foo").unwrap().unwrap(), "bar");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn store_lock_admits_one_holder_at_a_time() {
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        let first = StoreLock::acquire(&path, Duration::ZERO).unwrap();
+        let error = StoreLock::acquire(&path, Duration::from_millis(100))
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref(), Some(&StorageError::Locked));
+        assert_eq!(
+            error.to_string(),
+            "The store is locked by another srs process."
+        );
+
+        drop(first);
+        let second = StoreLock::acquire(&path, Duration::ZERO).unwrap();
+        drop(second);
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+
+    #[test]
+    fn store_lock_waits_for_the_holder_to_finish() {
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        let first = StoreLock::acquire(&path, Duration::ZERO).unwrap();
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(first);
+        });
+
+        assert!(StoreLock::acquire(&path, Duration::from_secs(10)).is_ok());
+        holder.join().unwrap();
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+
+    #[test]
+    fn write_store_rejects_content_that_does_not_parse() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+
+        assert!(write_store(&storage.file_path, b"{\"tokens\": tru").is_err());
+        let mut staging = storage.file_path.clone().into_os_string();
+        staging.push(".tmp");
+        assert!(!PathBuf::from(staging).exists());
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn load_rejects_corrupt_store() {
+        let mut storage = setup_storage();
+        std::fs::write(
+            &storage.file_path,
+            r#"{"tokens":{},"kdf":{"algorithm":"argon2id"}}"#,
+        )
+        .unwrap();
+        assert!(storage.load().is_err());
+    }
+
+    #[test]
+    fn open_fails_instead_of_starting_empty_when_the_store_is_unreadable() {
+        // A store "inside" a regular file cannot be read, yet does not
+        // count as missing either.
+        let blocker = std::env::temp_dir().join(format!("srs_test_{}", Uuid::new_v4()));
+        fs::write(&blocker, "").unwrap();
+        let path = blocker.join("srs.json");
+        assert!(!path.exists());
+
+        let error = TokenStorage::open_with_kdf(&path, "passphrase", Kdf::pbkdf2(1_000))
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("Could not read the store"));
+
+        let missing = blocker.with_extension("json");
+        let storage =
+            TokenStorage::open_with_kdf(&missing, "passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        assert!(storage.database.tokens.is_empty());
+        let _ = fs::remove_file(blocker);
+    }
+
+    #[test]
+    fn saved_store_does_not_reveal_token_names() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("deploy key".to_string()),
+            ..Default::default()
+        };
+        storage
+            .add_token("GITHUB_TOKEN", "ghp_secret", &options)
+            .unwrap();
+        storage.store_token("NPM_TOKEN", "npm_secret").unwrap();
+        storage.add_alias("GH", "GITHUB_TOKEN").unwrap();
+
+        let content = std::fs::read_to_string(&storage.file_path).unwrap();
+        for plaintext in [
+            "GITHUB_TOKEN",
+            "NPM_TOKEN",
+            "\"GH\"",
+            "deploy key",
+            "secret",
+        ] {
+            assert!(!content.contains(plaintext), "{plaintext} leaked");
+        }
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("GH").unwrap().unwrap(), "ghp_secret");
+        assert_eq!(
+            storage.get_token("NPM_TOKEN").unwrap().unwrap(),
+            "npm_secret"
+        );
+    }
+
+    #[test]
+    fn unversioned_stores_are_upgraded_to_the_current_version() {
+        let mut storage = setup_storage();
+        let encrypted = storage.crypto_manager.encrypt("bar").unwrap();
+        let legacy = serde_json::json!({ "tokens": { "foo": encrypted }, "revision": 2 });
+        std::fs::write(&storage.file_path, legacy.to_string()).unwrap();
+
+        let stored = read_database(&storage.file_path).unwrap().unwrap();
+        assert_eq!(stored.version, STORE_VERSION);
+        assert_eq!(stored.revision, 2);
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+        storage.save().unwrap();
+        let saved: Value =
+            serde_json::from_slice(&std::fs::read(&storage.file_path).unwrap()).unwrap();
+        assert_eq!(saved["version"], STORE_VERSION);
+    }
+
+    #[test]
+    fn stores_from_a_newer_version_are_refused() {
+        let mut storage = setup_storage();
+        let future = serde_json::json!({ "version": STORE_VERSION + 1, "tokens": [1, 2] });
+        std::fs::write(&storage.file_path, future.to_string()).unwrap();
+
+        let error = storage.load().unwrap_err().to_string();
+        assert!(error.contains("newer srs"), "{error}");
+    }
+
+    #[test]
+    fn dry_run_open_keeps_store_upgrades_in_memory() {
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        let legacy_crypto = CryptoManager::new("passphrase", &Kdf::LegacySha256).unwrap();
+        let legacy =
+            serde_json::json!({ "tokens": { "foo": legacy_crypto.encrypt("bar").unwrap() } });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        let storage =
+            TokenStorage::open_with(&path, "passphrase", Kdf::pbkdf2(1_000), true).unwrap();
+        assert!(matches!(storage.database.kdf, Kdf::Argon2id { .. }));
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+
+        TokenStorage::open_with(&path, "passphrase", Kdf::pbkdf2(1_000), false).unwrap();
+        assert_ne!(std::fs::read(&path).unwrap(), before);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn verify_values_reports_each_token_by_name() {
+        let path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
+        let crypto = CryptoManager::new("passphrase", &Kdf::LegacySha256).unwrap();
+        let other = CryptoManager::new("other", &Kdf::LegacySha256).unwrap();
+        // Old stores have no MAC, so a single value can go bad on its own.
+        let legacy = serde_json::json!({
+            "check": crypto.encrypt(KEY_CHECK).unwrap(),
+            "tokens": {
+                "good": crypto.encrypt("x").unwrap(),
+                "bad": other.encrypt("y").unwrap()
+            }
+        });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+        let content = std::fs::read(&path).unwrap();
+        let key = |key: &'static str| move || Ok(Zeroizing::new(key.to_string()));
+
+        let results = verify_values(&content, key("passphrase")).unwrap();
+        let names = results
+            .iter()
+            .map(|(name, result)| (name.as_str(), result.is_ok()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [("bad", false), ("good", true)]);
+        let error = verify_values(&content, key("wrong")).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
+        assert!(verify_values(b"{not json", || panic!("asked for the key")).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn plaintext_names_are_encrypted_on_first_open() {
+        let mut storage = setup_storage();
+        let encrypted = storage.crypto_manager.encrypt("bar").unwrap();
+        let legacy = serde_json::json!({
+            "tokens": { "GITHUB_TOKEN": encrypted },
+            "aliases": { "GH": "GITHUB_TOKEN" },
+            "revision": 3
+        });
+        std::fs::write(&storage.file_path, legacy.to_string()).unwrap();
+
+        storage.load().unwrap();
+        assert!(storage.migrate_plaintext_names().unwrap());
+        assert!(!storage.migrate_plaintext_names().unwrap());
+        assert_eq!(storage.database.revision, 4);
+
+        let content = std::fs::read_to_string(&storage.file_path).unwrap();
+        assert!(!content.contains("GITHUB_TOKEN"));
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("GH").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn profile_path_keeps_the_default_store() {
+        let data_dir = std::env::temp_dir().join(format!("srs_profiles_{}", Uuid::new_v4()));
+        assert_eq!(
+            profile_path(&data_dir, None).unwrap(),
+            data_dir.join("srs.json")
+        );
+        assert_eq!(
+            profile_path(&data_dir, Some(DEFAULT_PROFILE)).unwrap(),
+            data_dir.join("srs.json")
+        );
+        assert_eq!(
+            profile_path(&data_dir, Some("work")).unwrap(),
+            data_dir.join("profiles").join("work.json")
+        );
+        assert!(profile_path(&data_dir, Some("../escape")).is_err());
+        assert!(profile_path(&data_dir, Some("")).is_err());
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn store_path_follows_the_config() {
+        let data_dir = std::env::temp_dir().join(format!("srs_profiles_{}", Uuid::new_v4()));
+        let config = Config {
+            data_dir: Some(data_dir.clone()),
+            profile: Some("work".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            store_path(&config, None).unwrap(),
+            data_dir.join("profiles").join("work.json")
+        );
+        assert_eq!(
+            store_path(&config, Some(DEFAULT_PROFILE)).unwrap(),
+            data_dir.join("srs.json")
+        );
+        assert_eq!(
+            store_path(&Config::default(), None).unwrap(),
+            DATA_DIR.join("srs.json")
+        );
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn profiles_store_disjoint_tokens() {
+        let data_dir = std::env::temp_dir().join(format!("srs_profiles_{}", Uuid::new_v4()));
+        let mut work = setup_storage();
+        work.file_path = profile_path(&data_dir, Some("work")).unwrap();
+        let mut personal = setup_storage();
+        personal.file_path = profile_path(&data_dir, None).unwrap();
+
+        work.store_token("GITHUB_TOKEN", "work_value").unwrap();
+        personal
+            .store_token("GITHUB_TOKEN", "personal_value")
+            .unwrap();
+        personal.store_token("NPM_TOKEN", "npm").unwrap();
+
+        work.load().unwrap();
+        personal.load().unwrap();
+        assert_eq!(work.list_tokens().unwrap(), ["GITHUB_TOKEN"]);
+        assert_eq!(
+            work.get_token("GITHUB_TOKEN").unwrap().unwrap(),
+            "work_value"
+        );
+        assert_eq!(
+            personal.get_token("GITHUB_TOKEN").unwrap().unwrap(),
+            "personal_value"
+        );
+        assert_eq!(profiles_in(&data_dir).unwrap(), ["default", "work"]);
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn load_accepts_tokens_without_metadata() {
+        let mut storage = setup_storage();
+        let encrypted = storage.crypto_manager.encrypt("bar").unwrap();
+        let legacy = serde_json::json!({ "tokens": { "foo": encrypted }, "aliases": {} });
+        std::fs::write(&storage.file_path, legacy.to_string()).unwrap();
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+        let metadata = &storage.database.tokens["foo"].metadata;
+        assert!(metadata.created_at > 0);
+        assert_eq!(metadata.created_at, metadata.updated_at);
+        assert!(metadata.note.is_none());
+    }
+
+    #[test]
+    fn metadata_survives_save_and_load() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("deploy key".to_string()),
+            ..Default::default()
+        };
+        storage.add_token("foo", "bar", &options).unwrap();
+        let metadata = storage.database.tokens["foo"].metadata.clone();
+
+        storage.load().unwrap();
+        assert_eq!(storage.database.tokens["foo"].metadata, metadata);
+        assert_eq!(metadata.note.as_deref(), Some("deploy key"));
+    }
+
+    #[test]
+    fn overwriting_a_token_keeps_created_at_and_note() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("deploy key".to_string()),
+            ..Default::default()
+        };
+        storage.add_token("foo", "bar", &options).unwrap();
+        storage
+            .database
+            .tokens
+            .get_mut("foo")
+            .unwrap()
+            .metadata
+            .created_at = 1;
+
+        let options = AddOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        storage.add_token("foo", "baz", &options).unwrap();
+        let metadata = &storage.database.tokens["foo"].metadata;
+        assert_eq!(metadata.created_at, 1);
+        assert!(metadata.updated_at > 1);
+        assert_eq!(metadata.note.as_deref(), Some("deploy key"));
+    }
+
+    #[test]
+    fn add_token_stores_expiry() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            expires_at: Some(1_000),
+            ..Default::default()
+        };
+        storage.add_token("foo", "bar", &options).unwrap();
+        storage.add_alias("alias", "foo").unwrap();
+
+        storage.load().unwrap();
+        let metadata = storage.get_metadata("alias").unwrap();
+        assert_eq!(metadata.expires_at, Some(1_000));
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn expiry_status_uses_the_given_clock() {
+        let mut metadata = TokenMetadata::new();
+        assert_eq!(metadata.expiry(0), Expiry::Never);
+
+        metadata.expires_at = Some(10 * 24 * 60 * 60);
+        assert_eq!(metadata.expiry(0), Expiry::Valid);
+        assert_eq!(
+            metadata.expiry(5 * 24 * 60 * 60),
+            Expiry::Soon {
+                remaining: 5 * 24 * 60 * 60
+            }
+        );
+        assert_eq!(metadata.expiry(10 * 24 * 60 * 60), Expiry::Expired);
+        assert_eq!(metadata.expiry(11 * 24 * 60 * 60), Expiry::Expired);
+    }
+
+    #[test]
+    fn list_tokens_with_metadata_is_sorted() {
+        let mut storage = setup_storage();
+        storage.store_token("zeta", "1").unwrap();
+        storage.store_token("alpha", "2").unwrap();
+
+        let tokens = storage.list_tokens_with_metadata().unwrap();
+        let names: Vec<_> = tokens.iter().map(|token| token.name.as_str()).collect();
+        assert_eq!(names, ["alpha", "zeta"]);
+    }
+
+    fn storage_with(names: &[&str]) -> TokenStorage {
+        let mut storage = setup_storage();
+        for name in names {
+            storage.store_token(name, "value").unwrap();
+        }
+        storage
+    }
+
+    fn matching_names(storage: &TokenStorage, pattern: &str) -> Vec<String> {
+        storage
+            .list_tokens_matching(pattern)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.name)
+            .collect()
+    }
+
+    #[test]
+    fn list_tokens_matching_globs() {
+        let storage = storage_with(&["gh_token", "gh_app", "npm_token", "my_gh"]);
+        assert_eq!(matching_names(&storage, "gh*"), ["gh_app", "gh_token"]);
+        assert_eq!(
+            matching_names(&storage, "*_token"),
+            ["gh_token", "npm_token"]
+        );
+        assert_eq!(matching_names(&storage, "gh_?pp"), ["gh_app"]);
+    }
+
+    #[test]
+    fn list_tokens_matching_substrings() {
+        let storage = storage_with(&["gh_token", "npm_token", "aws_key"]);
+        assert_eq!(matching_names(&storage, "token"), ["gh_token", "npm_token"]);
+        assert!(matching_names(&storage, "missing").is_empty());
+    }
+
+    #[test]
+    fn list_tokens_matching_ignores_case() {
+        let storage = storage_with(&["GitHub_Token", "npm"]);
+        assert_eq!(matching_names(&storage, "github"), ["GitHub_Token"]);
+        assert_eq!(matching_names(&storage, "GITHUB*"), ["GitHub_Token"]);
+    }
+
+    #[test]
+    fn list_tokens_matching_treats_regex_characters_literally() {
+        let storage = storage_with(&["a.b", "axb"]);
+        assert_eq!(matching_names(&storage, "a.b"), ["a.b"]);
+        assert_eq!(matching_names(&storage, "a.*"), ["a.b"]);
+    }
+
+    #[test]
+    fn sort_tokens_by_timestamps() {
+        let mut storage = storage_with(&["b", "a"]);
+        storage
+            .database
+            .tokens
+            .get_mut("b")
+            .unwrap()
+            .metadata
+            .created_at = 1;
+        storage
+            .database
+            .tokens
+            .get_mut("a")
+            .unwrap()
+            .metadata
+            .updated_at = 1;
+
+        let mut tokens = storage.list_tokens_with_metadata().unwrap();
+        sort_tokens(&mut tokens, TokenSort::Created);
+        assert_eq!(tokens[0].name, "b");
+        sort_tokens(&mut tokens, TokenSort::Updated);
+        assert_eq!(tokens[0].name, "a");
+        sort_tokens(&mut tokens, TokenSort::Name);
+        assert_eq!(tokens[0].name, "a");
+    }
+
+    #[test]
+    fn rename_token_preserves_metadata() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            note: Some("deploy key".to_string()),
+            ..Default::default()
+        };
+        storage.add_token("old", "value", &options).unwrap();
+        let metadata = storage.database.tokens["old"].metadata.clone();
+
+        storage.rename_token("old", "new", false).unwrap();
+        assert_eq!(storage.database.tokens["new"].metadata, metadata);
+    }
+
+    #[test]
+    fn export_and_import_encrypted_round_trip() {
+        let mut source = setup_storage();
+        let options = AddOptions {
+            note: Some("deploy key".to_string()),
+            ..Default::default()
+        };
+        source.add_token("foo", "bar", &options).unwrap();
+        source.store_token("baz", "qux").unwrap();
+        source.add_alias("alias", "foo").unwrap();
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        assert_eq!(source.export_encrypted(&backup_path, "hunter2").unwrap(), 2);
+
+        let content = std::fs::read_to_string(&backup_path).unwrap();
+        assert!(!content.contains("foo") && !content.contains("bar"));
+
+        let mut target = setup_storage();
+        target.store_token("baz", "kept").unwrap();
+        let summary = target
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Skip)
+            .unwrap();
+        assert_eq!((summary.added, summary.skipped), (1, 1));
+        assert_eq!(target.get_token("alias").unwrap().unwrap(), "bar");
+        assert_eq!(target.get_token("baz").unwrap().unwrap(), "kept");
+        assert_eq!(
+            target.database.tokens["foo"].metadata.note.as_deref(),
+            Some("deploy key")
+        );
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn backups_taken_before_a_rekey_still_import() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        storage.export_encrypted(&backup_path, "hunter2").unwrap();
+
+        storage.rekey("new passphrase", Kdf::pbkdf2(1_000)).unwrap();
+        storage.delete_token("foo").unwrap();
+        let summary = storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Skip)
+            .unwrap();
+        assert_eq!(summary.added, 1);
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    fn export_sample_backup() -> PathBuf {
+        let mut source = setup_storage();
+        source.store_token("foo", "new").unwrap();
+        source.store_token("bar", "baz").unwrap();
+        source.add_alias("alias", "foo").unwrap();
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        source.export_encrypted(&backup_path, "hunter2").unwrap();
+        backup_path
+    }
+
+    #[test]
+    fn import_encrypted_skip_strategy_keeps_existing_tokens() {
+        let backup_path = export_sample_backup();
+        let mut storage = setup_storage();
+        storage.store_token("foo", "old").unwrap();
+
+        let summary = storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Skip)
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "old");
+        assert_eq!(storage.get_token("bar").unwrap().unwrap(), "baz");
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn import_encrypted_overwrite_strategy_replaces_existing_tokens() {
+        let backup_path = export_sample_backup();
+        let mut storage = setup_storage();
+        storage.store_token("foo", "old").unwrap();
+
+        let summary = storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Overwrite)
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                overwritten: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "new");
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn import_encrypted_rename_strategy_keeps_both_tokens() {
+        let backup_path = export_sample_backup();
+        let mut storage = setup_storage();
+        storage.store_token("foo", "old").unwrap();
+        storage.store_token("foo_imported", "older").unwrap();
+
+        let summary = storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Rename)
+            .unwrap();
+        assert_eq!(summary.added, 2);
+        assert_eq!(
+            summary.renamed,
+            [("foo".to_string(), "foo_imported_2".to_string())]
+        );
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "old");
+        assert_eq!(storage.get_token("foo_imported_2").unwrap().unwrap(), "new");
+        assert_eq!(storage.get_token("alias").unwrap().unwrap(), "new");
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn import_entries_follows_the_merge_strategy() {
+        let mut storage = setup_storage();
+        storage
+            .add_token("github.com", "old", &AddOptions::default())
+            .unwrap();
+        let entries = crate::import::parse_netrc(
+            "machine github.com login a password new\nmachine gitlab.com password gl\n",
+        )
+        .unwrap();
+
+        let summary = storage
+            .import_entries(&entries, MergeStrategy::Rename)
+            .unwrap();
+        assert_eq!(summary.added, 2);
+        assert_eq!(
+            summary.renamed,
+            [("github.com".to_string(), "github.com_imported".to_string())]
+        );
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("github.com").unwrap().unwrap(), "old");
+        assert_eq!(
+            storage.get_token("github.com_imported").unwrap().unwrap(),
+            "new"
+        );
+        assert_eq!(
+            storage
+                .get_metadata("github.com_imported")
+                .unwrap()
+                .note
+                .as_deref(),
+            Some("login: a")
+        );
+        assert_eq!(storage.get_token("gitlab.com").unwrap().unwrap(), "gl");
+    }
+
+    #[test]
+    fn import_entries_saves_the_valid_entries_and_reports_the_rest() {
+        let mut storage = setup_storage();
+        storage.store_token("existing", "old").unwrap();
+        storage.add_alias("GH", "existing").unwrap();
+        let entry = |name: &str, value: &str, metadata: Option<TokenMetadata>| TokenDump {
+            name: name.to_string(),
+            value: value.to_string(),
+            metadata,
+        };
+        let entries = [
+            entry("github", "ghp_abc", None),
+            entry(
+                "blob",
+                "not base64!",
+                Some(TokenMetadata {
+                    binary: true,
+                    ..TokenMetadata::new()
+                }),
+            ),
+            entry("GH", "aliased", None),
+            entry(
+                "pin",
+                "12ab",
+                Some(TokenMetadata {
+                    pattern: Some("^[0-9]+$".to_string()),
+                    ..TokenMetadata::new()
+                }),
+            ),
+            entry("existing", "new", None),
+            entry("npm", "npm_abc", None),
+        ];
+
+        let summary = storage
+            .import_entries(&entries, MergeStrategy::Overwrite)
+            .unwrap();
+        assert_eq!((summary.added, summary.overwritten), (2, 1));
+        let failed = summary
+            .failed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["blob", "GH", "pin"]);
+        assert!(summary.failed[0].1.contains("not valid base64"));
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("github").unwrap().unwrap(), "ghp_abc");
+        assert_eq!(storage.get_token("npm").unwrap().unwrap(), "npm_abc");
+        assert_eq!(storage.get_token("existing").unwrap().unwrap(), "new");
+        assert!(storage.get_metadata("blob").is_none());
+        assert!(storage.get_metadata("pin").is_none());
+
+        // Plain imports, such as unseal, import nothing if an entry fails.
+        let entries = [entry("later", "x", None), entry("GH", "aliased", None)];
+        let error = storage.import_tokens(&entries, true).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Could not import 'GH': 'GH' already exists as an alias"
+        );
+        assert!(storage.get_metadata("later").is_none());
+    }
+
+    #[test]
+    fn import_encrypted_rejects_corrupt_backup_without_changes() {
+        let backup_path = export_sample_backup();
+        let content = std::fs::read_to_string(&backup_path).unwrap();
+        std::fs::write(&backup_path, content.replace("payload", "pay_load")).unwrap();
+
+        let mut storage = setup_storage();
+        storage.store_token("foo", "old").unwrap();
+        let revision = storage.database.revision;
+        assert!(storage
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Overwrite)
+            .is_err());
+        assert_eq!(storage.database.revision, revision);
+        assert_eq!(storage.list_tokens().unwrap(), ["foo"]);
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn import_encrypted_with_wrong_password_leaves_store_untouched() {
+        let mut source = setup_storage();
+        source.store_token("foo", "bar").unwrap();
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        source.export_encrypted(&backup_path, "hunter2").unwrap();
+
+        let mut target = setup_storage();
+        target.store_token("foo", "old").unwrap();
+        let revision = target.database.revision;
+        assert!(target
+            .import_encrypted(&backup_path, "wrong", MergeStrategy::Overwrite)
+            .is_err());
+        assert_eq!(target.database.revision, revision);
+        assert_eq!(target.get_token("foo").unwrap().unwrap(), "old");
+        let _ = std::fs::remove_file(backup_path);
+    }
+
+    #[test]
+    fn rename_token_rebinds_ciphertext() {
+        let mut storage = setup_storage();
+        storage.store_token("old", "value").unwrap();
+        storage.add_alias("alias", "old").unwrap();
+        let ciphertext = storage.database.tokens["old"].ciphertext.clone();
+
+        storage.rename_token("old", "new", false).unwrap();
+        assert!(storage.get_token("old").unwrap().is_none());
+        assert_ne!(storage.database.tokens["new"].ciphertext, ciphertext);
+        assert_eq!(storage.get_token("new").unwrap().unwrap(), "value");
+        assert_eq!(storage.get_token("alias").unwrap().unwrap(), "value");
+    }
+
+    #[test]
+    fn values_moved_under_another_name_fail_to_decrypt() {
+        let mut storage = setup_storage();
+        storage.store_token("prod-db", "prod").unwrap();
+        storage.store_token("test-db", "test").unwrap();
+
+        let prod = storage.database.tokens["prod-db"].ciphertext.clone();
+        let test = storage.database.tokens["test-db"].ciphertext.clone();
+        storage
+            .database
+            .tokens
+            .get_mut("prod-db")
+            .unwrap()
+            .ciphertext = test;
+        storage
+            .database
+            .tokens
+            .get_mut("test-db")
+            .unwrap()
+            .ciphertext = prod;
+
+        assert!(storage.get_token("prod-db").is_err());
+        assert!(storage.get_token("test-db").is_err());
+    }
+
+    #[test]
+    fn values_swapped_in_the_file_are_rejected() {
+        let mut storage = setup_storage();
+        storage.store_token("prod-db", "prod").unwrap();
+        storage.store_token("test-db", "test").unwrap();
+
+        tamper_with_store(&storage, |stored| {
+            let values = stored["values"].as_object_mut().unwrap();
+            let first = values["0"].clone();
+            values.insert("0".to_string(), values["1"].clone());
+            values.insert("1".to_string(), first);
+        });
+        assert_integrity_failure(&mut storage);
+    }
+
+    fn tamper_with_store(storage: &TokenStorage, change: impl FnOnce(&mut Value)) {
+        let content = std::fs::read_to_string(&storage.file_path).unwrap();
+        let mut stored: Value = serde_json::from_str(&content).unwrap();
+        change(&mut stored);
+        std::fs::write(&storage.file_path, stored.to_string()).unwrap();
+    }
+
+    fn assert_integrity_failure(storage: &mut TokenStorage) {
+        let error = storage.load().err().unwrap();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::IntegrityCheckFailed)
+        );
+    }
+
+    fn add_tagged(storage: &mut TokenStorage, name: &str, tags: &[&str]) {
+        let options = AddOptions {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        };
+        storage.add_token(name, "value", &options).unwrap();
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn tags_are_normalized_and_saved() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "aws_key", &["work", "aws", "work"]);
+        storage.load().unwrap();
+
+        assert_eq!(
+            storage.get_metadata("aws_key").unwrap().tags,
+            ["aws", "work"]
+        );
+        let options = AddOptions {
+            tags: tags(&["has space"]),
+            ..Default::default()
+        };
+        assert!(storage.add_token("bad", "value", &options).is_err());
+        assert!(storage.get_metadata("bad").is_none());
+    }
+
+    #[test]
+    fn overwrite_keeps_tags_unless_new_ones_are_given() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "token", &["work"]);
+        let overwrite = AddOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        storage.add_token("token", "new", &overwrite).unwrap();
+        assert_eq!(storage.get_metadata("token").unwrap().tags, ["work"]);
+
+        let retag = AddOptions {
+            overwrite: true,
+            tags: tags(&["personal"]),
+            ..Default::default()
+        };
+        storage.add_token("token", "newer", &retag).unwrap();
+        assert_eq!(storage.get_metadata("token").unwrap().tags, ["personal"]);
+    }
+
+    #[test]
+    fn listing_filters_by_tags() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "aws_key", &["work", "aws"]);
+        add_tagged(&mut storage, "github", &["work", "github"]);
+        add_tagged(&mut storage, "home_wifi", &[]);
 
-impl TokenStorage {
-    pub fn new() -> Result<Self> {
-        let crypto_manager: CryptoManager = CryptoManager::new()?;
-        let mut storage = Self {
-            file_path: CONFIG_PATH.to_path_buf(),
-            database: TokenDatabase {
-                tokens: HashMap::new(),
-                aliases: HashMap::new(),
-            },
-            crypto_manager,
+        let tagged = |wanted: &[&str]| {
+            let wanted = tags(wanted);
+            storage
+                .list_tokens_with_metadata()
+                .unwrap()
+                .into_iter()
+                .filter(|token| token.metadata.has_tags(&wanted))
+                .map(|token| token.name)
+                .collect::<Vec<_>>()
         };
+        assert_eq!(tagged(&["work"]), ["aws_key", "github"]);
+        assert_eq!(tagged(&["work", "aws"]), ["aws_key"]);
+        assert!(tagged(&["missing"]).is_empty());
+        assert_eq!(tagged(&[]).len(), 3);
+    }
 
-        storage.load()?;
-        Ok(storage)
+    #[test]
+    fn token_env_only_exports_tagged_tokens() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "AWS_KEY", &["work", "aws"]);
+        add_tagged(&mut storage, "HOME_TOKEN", &["personal"]);
+        storage.add_alias("AWS_ALIAS", "AWS_KEY").unwrap();
+        storage.add_alias("HOME_ALIAS", "HOME_TOKEN").unwrap();
+
+        let options = ChildEnvOptions {
+            tags: tags(&["work"]),
+            ..Default::default()
+        };
+        let env = storage.token_env(&options).unwrap();
+        let mut names = env.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["AWS_ALIAS", "AWS_KEY"]);
+
+        let child_env = storage.build_child_env(&options).unwrap();
+        assert!(child_env.contains_key("AWS_KEY"));
+        assert!(!child_env.contains_key("HOME_TOKEN"));
     }
 
-    fn load(&mut self) -> Result<()> {
-        if Path::new(&self.file_path).exists() {
-            let content = fs::read_to_string(&self.file_path)?;
-            self.database = serde_json::from_str(&content)?;
+    #[test]
+    fn token_env_exports_under_the_env_name() {
+        let mut storage = setup_storage();
+        let options = AddOptions {
+            env_name: Some("GITHUB_TOKEN".to_string()),
+            ..Default::default()
+        };
+        storage
+            .add_token("github_personal", "ghp_abc", &options)
+            .unwrap();
+        storage.store_token("npm", "npm_abc").unwrap();
+        storage.add_alias("gh", "github_personal").unwrap();
+
+        let env = storage.token_env(&ChildEnvOptions::default()).unwrap();
+        assert_eq!(env["GITHUB_TOKEN"], "ghp_abc");
+        assert_eq!(env["gh"], "ghp_abc");
+        assert!(!env.contains_key("github_personal"));
+
+        // The prefix and upper-casing only apply to names derived from tokens.
+        let env = storage
+            .token_env(&ChildEnvOptions {
+                env_prefix: "SRS_".to_string(),
+                upper: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut names = env.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["GITHUB_TOKEN", "SRS_GH", "SRS_NPM"]);
+
+        let env = storage
+            .token_env(&ChildEnvOptions {
+                only: vec!["github_personal".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(env.keys().collect::<Vec<_>>(), ["GITHUB_TOKEN"]);
+    }
+
+    #[test]
+    fn env_name_wins_over_a_token_of_that_name() {
+        let mut storage = setup_storage();
+        storage.store_token("GITHUB_TOKEN", "old").unwrap();
+        let options = AddOptions {
+            env_name: Some("GITHUB_TOKEN".to_string()),
+            ..Default::default()
+        };
+        storage
+            .add_token("github_personal", "new", &options)
+            .unwrap();
+
+        let env = storage.token_env(&ChildEnvOptions::default()).unwrap();
+        assert_eq!(env.len(), 1);
+        assert_eq!(env["GITHUB_TOKEN"], "new");
+    }
+
+    #[test]
+    fn add_token_rejects_an_invalid_env_name() {
+        let mut storage = setup_storage();
+        for var in ["GITHUB-TOKEN", "1TOKEN", ""] {
+            let options = AddOptions {
+                env_name: Some(var.to_string()),
+                ..Default::default()
+            };
+            let error = storage.add_token("github", "ghp", &options).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("'{var}' is not a valid environment variable name")
+            );
         }
-        Ok(())
     }
 
-    fn save(&self) -> Result<()> {
-        let content = serde_json::to_string_pretty(&self.database)?;
-        fs::write(&self.file_path, content)?;
-        Ok(())
+    #[test]
+    fn token_env_leaves_out_excluded_tokens_and_their_aliases() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "AWS_KEY", &["work"]);
+        add_tagged(&mut storage, "GITHUB", &["work"]);
+        add_tagged(&mut storage, "NPM", &["work"]);
+        add_tagged(&mut storage, "HOME_TOKEN", &["personal"]);
+        storage.add_alias("AWS_ALIAS", "AWS_KEY").unwrap();
+        storage.add_alias("GH", "GITHUB").unwrap();
+
+        let exported = |options: ChildEnvOptions| {
+            let mut names = storage
+                .token_env(&options)
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let strings = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+
+        // Excluding a token takes its aliases along, excluding an alias does not.
+        assert_eq!(
+            exported(ChildEnvOptions {
+                exclude: strings(&["AWS_KEY", "GH", "NO_SUCH_TOKEN"]),
+                ..Default::default()
+            }),
+            ["GITHUB", "HOME_TOKEN", "NPM"]
+        );
+        assert_eq!(
+            exported(ChildEnvOptions {
+                only: strings(&["AWS_KEY", "AWS_ALIAS", "GITHUB"]),
+                exclude: strings(&["AWS_ALIAS"]),
+                ..Default::default()
+            }),
+            ["AWS_KEY", "GITHUB"]
+        );
+        assert_eq!(
+            exported(ChildEnvOptions {
+                tags: tags(&["work"]),
+                exclude: strings(&["GITHUB"]),
+                ..Default::default()
+            }),
+            ["AWS_ALIAS", "AWS_KEY", "NPM"]
+        );
     }
 
-    pub fn store_token(&mut self, name: &str, token: &str) -> Result<()> {
-        let encrypted_token = self.crypto_manager.encrypt(token)?;
-        self.database
+    #[test]
+    fn tags_survive_dump_and_import() {
+        let mut storage = setup_storage();
+        add_tagged(&mut storage, "aws_key", &["work"]);
+        let dump = storage.dump_tokens().unwrap();
+
+        let mut restored = setup_storage();
+        restored.import_tokens(&dump, false).unwrap();
+        assert_eq!(restored.get_metadata("aws_key").unwrap().tags, ["work"]);
+    }
+
+    #[test]
+    fn expiry_and_created_at_survive_export_and_import() {
+        let mut source = setup_storage();
+        let options = AddOptions {
+            expires_at: Some(now() + 60),
+            ..Default::default()
+        };
+        source.add_token("deploy", "value", &options).unwrap();
+        source
+            .database
             .tokens
-            .insert(name.to_string(), encrypted_token);
-        self.save()?;
-        Ok(())
+            .get_mut("deploy")
+            .unwrap()
+            .metadata
+            .created_at = 1;
+        let backup_path = std::env::temp_dir().join(format!("srs_backup_{}.json", Uuid::new_v4()));
+        source.export_encrypted(&backup_path, "hunter2").unwrap();
+
+        let mut target = setup_storage();
+        target
+            .import_encrypted(&backup_path, "hunter2", MergeStrategy::Skip)
+            .unwrap();
+        let metadata = target.get_metadata("deploy").unwrap();
+        assert_eq!(metadata.expires_at, options.expires_at);
+        assert_eq!(metadata.created_at, 1);
+        assert!(matches!(metadata.expiry(now()), Expiry::Soon { .. }));
+        let _ = std::fs::remove_file(backup_path);
     }
 
-    pub fn get_token(&self, name: &str) -> Result<Option<String>> {
-        let actual_name = self.resolve_alias(name);
-        match self.database.tokens.get(actual_name) {
-            Some(encrypted_token) => {
-                let decrypted_token = self.crypto_manager.decrypt(encrypted_token)?;
-                Ok(Some(decrypted_token))
-            }
-            None => Ok(None),
-        }
+    #[cfg(unix)]
+    #[test]
+    fn resolve_shell_prefers_an_explicit_shell() {
+        let shell = resolve_shell(Some(Path::new("/bin/sh")), Some("/bin/zsh".to_string()));
+        assert_eq!(shell.unwrap(), PathBuf::from("/bin/sh"));
+
+        let by_name = resolve_shell(Some(Path::new("sh")), None).unwrap();
+        assert!(by_name.is_absolute() && by_name.ends_with("sh"));
     }
 
-    fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
-        self.database
-            .aliases
-            .get(name)
-            .map_or(name, std::string::String::as_str)
+    #[cfg(unix)]
+    #[test]
+    fn resolve_shell_rejects_missing_or_non_executable_shells() {
+        let missing = resolve_shell(Some(Path::new("/nonexistent/shell")), None);
+        assert!(missing.unwrap_err().to_string().contains("not executable"));
+
+        let file = std::env::temp_dir().join(format!("srs_shell_{}", uuid::Uuid::new_v4()));
+        fs::write(&file, "#!/bin/sh\n").unwrap();
+        assert!(resolve_shell(Some(&file), None).is_err());
+        let _ = fs::remove_file(file);
     }
 
-    pub fn list_tokens(&self) -> Result<Vec<String>> {
-        let _ = self.verify_master_key()?;
-        Ok(self.database.tokens.keys().cloned().collect())
+    #[test]
+    fn resolve_shell_falls_back_to_env_then_default() {
+        assert_eq!(
+            resolve_shell(None, Some("/usr/bin/fish".to_string())).unwrap(),
+            PathBuf::from("/usr/bin/fish")
+        );
+        assert_eq!(
+            resolve_shell(None, Some(String::new())).unwrap(),
+            default_shell()
+        );
+        assert_eq!(resolve_shell(None, None).unwrap(), default_shell());
     }
 
-    fn verify_master_key(&self) -> Result<bool> {
-        if self.database.tokens.is_empty() {
-            return Err(anyhow::anyhow!(
-                "No tokens found, please add a token to start."
-            ));
-        }
+    const NOT_UTF8: &[u8] = &[0x00, 0xff, 0xfe, 0x80, b'k', b'e', b'y', 0xc3, 0x28, 0x0a];
 
-        if let Some((_, encrypted_token)) = self.database.tokens.iter().next() {
-            Ok(self.crypto_manager.decrypt(encrypted_token).is_ok())
-        } else {
-            Err(anyhow::anyhow!(
-                "Incorrect master key. Cannot delete token."
-            ))
-        }
-    }
+    #[test]
+    fn binary_values_round_trip() {
+        let mut storage = setup_storage();
+        storage
+            .add_bytes("gpg_blob", NOT_UTF8, &AddOptions::default())
+            .unwrap();
+        storage.add_alias("blob_alias", "gpg_blob").unwrap();
 
-    pub fn delete_token(&mut self, name: &str) -> Result<bool> {
-        let _ = self.verify_master_key()?;
+        assert_eq!(storage.get_bytes("gpg_blob").unwrap().unwrap(), NOT_UTF8);
+        assert_eq!(storage.get_bytes("blob_alias").unwrap().unwrap(), NOT_UTF8);
+        assert!(storage.get_metadata("gpg_blob").unwrap().binary);
+        assert!(storage.get_token("gpg_blob").is_err());
+        assert!(storage.get_bytes("missing").unwrap().is_none());
 
-        let removed = self.database.tokens.remove(name).is_some();
-        if removed {
-            self.database.aliases.retain(|_, target| target != name);
-            self.save()?;
-            println!("::> Token '{name}' deleted successfully!");
-        } else {
-            println!("::> Token '{name}' not found");
-        }
-        Ok(removed)
+        storage.load().unwrap();
+        assert_eq!(storage.get_bytes("gpg_blob").unwrap().unwrap(), NOT_UTF8);
+        assert!(storage.get_metadata("gpg_blob").unwrap().binary);
     }
 
-    pub fn populate_tokens_to_child(&self) -> Result<()> {
-        let _ = self.verify_master_key()?;
+    #[test]
+    fn text_tokens_stay_text() {
+        let mut storage = setup_storage();
+        storage
+            .add_bytes("key", NOT_UTF8, &AddOptions::default())
+            .unwrap();
+        let options = AddOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        storage.add_token("key", "text again", &options).unwrap();
 
-        let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+        assert!(!storage.get_metadata("key").unwrap().binary);
+        assert_eq!(storage.get_token("key").unwrap().unwrap(), "text again");
+        assert_eq!(storage.get_bytes("key").unwrap().unwrap(), b"text again");
+    }
 
-        let mut child_env = std::env::vars().collect::<HashMap<String, String>>();
+    #[test]
+    fn binary_values_survive_dump_and_import() {
+        let mut storage = setup_storage();
+        storage
+            .add_bytes("gpg_blob", NOT_UTF8, &AddOptions::default())
+            .unwrap();
+        storage.store_token("text", "plain").unwrap();
+        let dump = storage.dump_tokens().unwrap();
 
-        for (name, encrypted_token) in &self.database.tokens {
-            let decrypted_token = self.crypto_manager.decrypt(encrypted_token)?;
-            child_env.insert(name.clone(), decrypted_token);
-        }
+        let mut restored = setup_storage();
+        assert_eq!(restored.import_tokens(&dump, false).unwrap(), (2, 0));
+        assert_eq!(restored.get_bytes("gpg_blob").unwrap().unwrap(), NOT_UTF8);
+        assert!(restored.get_metadata("gpg_blob").unwrap().binary);
+        assert_eq!(restored.get_token("text").unwrap().unwrap(), "plain");
 
-        for (alias, target) in &self.database.aliases {
-            if let Some(value) = child_env.get(target) {
-                child_env.insert(alias.clone(), value.clone());
-            }
-        }
+        restored.copy_token("gpg_blob", "copy").unwrap();
+        assert_eq!(restored.get_bytes("copy").unwrap().unwrap(), NOT_UTF8);
+        assert!(restored.get_metadata("copy").unwrap().binary);
+    }
 
-        let mut child = std::process::Command::new(&shell)
-            .envs(&child_env)
-            .spawn()?;
+    #[test]
+    fn binary_values_are_not_exported_to_the_environment() {
+        let mut storage = setup_storage();
+        storage
+            .add_bytes("gpg_blob", NOT_UTF8, &AddOptions::default())
+            .unwrap();
+        storage.store_token("TEXT", "plain").unwrap();
 
-        child.wait()?;
-        Ok(())
+        let env = storage.token_env(&ChildEnvOptions::default()).unwrap();
+        assert_eq!(env.keys().collect::<Vec<_>>(), ["TEXT"]);
+
+        let only = ChildEnvOptions {
+            only: vec!["gpg_blob".to_string()],
+            ..Default::default()
+        };
+        assert!(storage.token_env(&only).is_err());
     }
 
-    pub fn add_alias(&mut self, alias: &str, target: &str) -> Result<()> {
-        let _ = self.verify_master_key()?;
+    #[test]
+    fn flipped_byte_in_the_file_is_detected() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
 
-        if !self.database.tokens.contains_key(target) {
-            return Err(anyhow::anyhow!("Target token '{target}' does not exist"));
-        }
+        tamper_with_store(&storage, |stored| {
+            let value = stored["values"]["0"].as_str().unwrap();
+            let mut bytes = general_purpose::STANDARD.decode(value).unwrap();
+            bytes[20] ^= 1;
+            stored["values"]["0"] = general_purpose::STANDARD.encode(bytes).into();
+        });
+        assert_integrity_failure(&mut storage);
+    }
 
-        if self.database.tokens.contains_key(alias) {
-            return Err(anyhow::anyhow!("'{alias}' already exists as a token name"));
-        }
+    #[test]
+    fn dropped_entry_is_detected() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("baz", "qux").unwrap();
 
-        if self.database.aliases.contains_key(alias) {
-            return Err(anyhow::anyhow!("Alias '{alias}' already exists"));
-        }
+        tamper_with_store(&storage, |stored| {
+            stored["values"].as_object_mut().unwrap().remove("1");
+        });
+        assert_integrity_failure(&mut storage);
+    }
 
-        self.database
-            .aliases
-            .insert(alias.to_string(), target.to_string());
-        self.save()?;
-        Ok(())
+    #[test]
+    fn changed_revision_is_detected() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+
+        tamper_with_store(&storage, |stored| stored["revision"] = 99.into());
+        assert_integrity_failure(&mut storage);
     }
 
-    pub fn remove_alias(&mut self, alias: &str) -> Result<bool> {
-        let _ = self.verify_master_key()?;
+    #[test]
+    fn stripped_mac_is_detected() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
 
-        let removed = self.database.aliases.remove(alias).is_some();
-        if removed {
-            self.save()?;
-        }
-        Ok(removed)
+        tamper_with_store(&storage, |stored| {
+            stored.as_object_mut().unwrap().remove("mac");
+        });
+        assert_integrity_failure(&mut storage);
     }
 
-    pub fn list_aliases(&self) -> Result<Vec<(String, String)>> {
-        if !self.database.tokens.is_empty() {
-            let _ = self.verify_master_key()?;
-        }
-        Ok(self
-            .database
-            .aliases
-            .iter()
-            .map(|(alias, target)| (alias.clone(), target.clone()))
-            .collect())
+    #[test]
+    fn stores_without_a_mac_gain_one_on_save() {
+        let mut storage = setup_storage();
+        storage.store_token("foo", "bar").unwrap();
+
+        // A store written before MACs existed has neither the MAC nor the
+        // flag in its index.
+        let mut stored = storage.database.seal(&storage.crypto_manager).unwrap();
+        let mut names: NameIndex = serde_json::from_str(
+            &storage
+                .crypto_manager
+                .decrypt(stored.index.as_ref().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        names.authenticated = false;
+        stored.index = Some(
+            storage
+                .crypto_manager
+                .encrypt(&serde_json::to_string(&names).unwrap())
+                .unwrap(),
+        );
+        stored.mac = None;
+        std::fs::write(&storage.file_path, serde_json::to_vec(&stored).unwrap()).unwrap();
+
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+        storage.store_token("baz", "qux").unwrap();
+        let content = std::fs::read_to_string(&storage.file_path).unwrap();
+        assert!(serde_json::from_str::<Value>(&content).unwrap()["mac"].is_string());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use uuid::Uuid;
+    #[test]
+    fn unbound_values_are_bound_on_open() {
+        let mut storage = setup_storage();
+        let encrypted = storage.crypto_manager.encrypt("bar").unwrap();
+        let legacy = serde_json::json!({ "tokens": { "foo": encrypted } });
+        std::fs::write(&storage.file_path, legacy.to_string()).unwrap();
 
-    fn setup_storage() -> TokenStorage {
-        let temp_path = std::env::temp_dir().join(format!("srs_test_{}.json", Uuid::new_v4()));
-        if temp_path.exists() {
-            let _ = std::fs::remove_file(&temp_path);
-        }
+        storage.load().unwrap();
+        assert!(storage.migrate_unbound_values().unwrap());
+        assert!(!storage.migrate_unbound_values().unwrap());
 
-        let key = [0u8; 32];
-        let crypto_manager = CryptoManager::from_key(key);
+        let ciphertext = &storage.database.tokens["foo"].ciphertext;
+        assert!(storage.crypto_manager.decrypt(ciphertext).is_err());
+        storage.load().unwrap();
+        assert!(!storage.database.unbound_values);
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
 
-        let mut storage = TokenStorage {
-            file_path: temp_path,
-            database: TokenDatabase {
-                tokens: HashMap::new(),
-                aliases: HashMap::new(),
-            },
-            crypto_manager,
-        };
+    #[test]
+    fn copy_token_duplicates_the_value() {
+        let mut storage = setup_storage();
+        storage.store_token("prod", "secret").unwrap();
+        storage.add_alias("current", "prod").unwrap();
+
+        storage.copy_token("prod", "staging").unwrap();
+        storage.copy_token("current", "dev").unwrap();
+        assert_eq!(storage.get_token("staging").unwrap().unwrap(), "secret");
+        assert_eq!(storage.get_token("dev").unwrap().unwrap(), "secret");
+        assert_eq!(storage.get_token("prod").unwrap().unwrap(), "secret");
+        assert_ne!(
+            storage.database.tokens["staging"].ciphertext,
+            storage.database.tokens["prod"].ciphertext
+        );
 
         storage.load().unwrap();
-        storage
+        assert_eq!(storage.get_token("staging").unwrap().unwrap(), "secret");
     }
 
     #[test]
-    fn store_and_get_token() {
+    fn copy_token_fails_for_a_missing_source() {
         let mut storage = setup_storage();
-        storage.store_token("foo", "bar").unwrap();
+        storage.store_token("prod", "secret").unwrap();
 
-        let token = storage.get_token("foo").unwrap();
-        assert_eq!(token.unwrap(), "bar");
+        let error = storage.copy_token("missing", "staging").unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::NotFound("missing".to_string()))
+        );
+        assert!(storage.get_token("staging").unwrap().is_none());
     }
 
     #[test]
-    fn get_nonexistent_token() {
-        let storage = setup_storage();
-        let token = storage.get_token("nonexistent").unwrap();
-        assert!(token.is_none());
+    fn copy_token_refuses_an_existing_destination() {
+        let mut storage = setup_storage();
+        storage.store_token("prod", "secret").unwrap();
+        storage.store_token("staging", "kept").unwrap();
+
+        let error = storage.copy_token("prod", "staging").unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::AlreadyExists("staging".to_string()))
+        );
+        assert_eq!(storage.get_token("staging").unwrap().unwrap(), "kept");
     }
 
     #[test]
-    fn delete_token() {
+    fn rename_missing_token_fails() {
         let mut storage = setup_storage();
         storage.store_token("foo", "bar").unwrap();
-        let deleted = storage.delete_token("foo").unwrap();
-        assert!(deleted);
-
-        let token = storage.get_token("foo").unwrap();
-        assert!(token.is_none());
+        let result = storage.rename_token("missing", "new", false);
+        assert_eq!(
+            result.unwrap_err().downcast_ref(),
+            Some(&StorageError::NotFound("missing".to_string()))
+        );
     }
 
     #[test]
-    fn delete_nonexistent_token() {
+    fn rename_to_existing_token_requires_force() {
         let mut storage = setup_storage();
-        let result = storage.delete_token("nonexistent").is_err();
-        assert!(result);
+        storage.store_token("old", "old_value").unwrap();
+        storage.store_token("new", "new_value").unwrap();
+
+        let error = storage.rename_token("old", "new", false).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&StorageError::AlreadyExists("new".to_string()))
+        );
+        assert_eq!(storage.get_token("new").unwrap().unwrap(), "new_value");
+        assert_eq!(storage.get_token("old").unwrap().unwrap(), "old_value");
+
+        storage.rename_token("old", "new", true).unwrap();
+        assert_eq!(storage.get_token("new").unwrap().unwrap(), "old_value");
+        assert!(storage.get_token("old").unwrap().is_none());
     }
 
     #[test]
-    fn list_tokens() {
+    fn load_with_correct_key_passes_the_key_check() {
         let mut storage = setup_storage();
         storage.store_token("foo", "bar").unwrap();
-        storage.store_token("baz", "qux").unwrap();
 
-        let tokens = storage.list_tokens().unwrap();
-        assert!(tokens.contains(&"foo".to_string()));
-        assert!(tokens.contains(&"baz".to_string()));
+        let content = std::fs::read_to_string(&storage.file_path).unwrap();
+        assert!(content.contains("\"check\""));
+        storage.load().unwrap();
+        assert_eq!(storage.get_token("foo").unwrap().unwrap(), "bar");
+    }
+
+    #[test]
+    fn load_checks_legacy_stores_against_their_first_value() {
+        let mut storage = setup_storage();
+        let encrypted = storage.crypto_manager.encrypt("bar").unwrap();
+        let legacy = serde_json::json!({ "tokens": { "foo": encrypted } });
+        std::fs::write(&storage.file_path, legacy.to_string()).unwrap();
+        storage.load().unwrap();
+
+        storage.crypto_manager = CryptoManager::from_key([1u8; 32]);
+        let error = storage.load().err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
     }
 
     #[test]
-    fn verify_master_key_with_tokens() {
+    fn load_with_wrong_key_fails_the_key_check() {
         let mut storage = setup_storage();
         storage.store_token("foo", "bar").unwrap();
-        let verified = storage.verify_master_key().unwrap();
-        assert!(verified);
+        storage.database.tokens.clear();
+        storage.save().unwrap();
+
+        storage.crypto_manager = CryptoManager::from_key([1u8; 32]);
+        let error = storage.load().err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
     }
 
     #[test]
@@ -284,11 +4901,12 @@ mod tests {
 
         let mut storage2 = TokenStorage {
             file_path: temp_path.to_path_buf(),
-            database: TokenDatabase {
-                tokens: HashMap::new(),
-                aliases: HashMap::new(),
-            },
+            database: TokenDatabase::default(),
             crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run: false,
+            in_memory: false,
         };
 
         storage.store_token("foo", "bar").unwrap();
@@ -298,6 +4916,23 @@ mod tests {
         assert_eq!(token.unwrap(), "bar");
     }
 
+    #[test]
+    fn in_memory_store_never_touches_the_disk() {
+        let mut storage = TokenStorage::in_memory(CryptoManager::from_key([3; 32]));
+        storage.set_audit(true);
+        storage
+            .add_token("github", "ghp_abc", &AddOptions::default())
+            .unwrap();
+        storage.rename_token("github", "gh", false).unwrap();
+        assert!(storage.audit_events().unwrap().is_empty());
+        assert!(!storage.file_path.exists());
+
+        // The blob still opens with the same key, e.g. to save it elsewhere.
+        let stored = parse_database(&storage.to_blob().unwrap()).unwrap();
+        let database = stored.open(&CryptoManager::from_key([3; 32])).unwrap();
+        assert_eq!(database.tokens.len(), 1);
+    }
+
     #[test]
     fn add_and_get_alias() {
         let mut storage = setup_storage();
@@ -463,8 +5098,7 @@ mod tests {
         let mut storage = setup_storage();
         storage.store_token("SOLO_TOKEN", "value").unwrap();
 
-        let deleted = storage.delete_token("SOLO_TOKEN").unwrap();
-        assert!(deleted);
+        storage.delete_token("SOLO_TOKEN").unwrap();
 
         assert!(storage.get_token("SOLO_TOKEN").unwrap().is_none());
         assert_eq!(storage.list_aliases().unwrap().len(), 0);
@@ -483,11 +5117,12 @@ mod tests {
         let crypto_manager = CryptoManager::from_key(key);
         let mut storage2 = TokenStorage {
             file_path: temp_path,
-            database: TokenDatabase {
-                tokens: HashMap::new(),
-                aliases: HashMap::new(),
-            },
+            database: TokenDatabase::default(),
             crypto_manager,
+            audit_log: None,
+            lock: None,
+            dry_run: false,
+            in_memory: false,
         };
         storage2.load().unwrap();
 
@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+pub trait SyncBackend {
+    fn push(&self, blob: &[u8]) -> Result<()>;
+    fn pull(&self) -> Result<Option<Vec<u8>>>;
+}
+
+pub struct FsSyncBackend {
+    path: PathBuf,
+}
+
+impl FsSyncBackend {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl SyncBackend for FsSyncBackend {
+    fn push(&self, blob: &[u8]) -> Result<()> {
+        let staging = self.path.with_extension("srs-sync-tmp");
+        fs::write(&staging, blob)?;
+        fs::rename(&staging, &self.path)?;
+        Ok(())
+    }
+
+    fn pull(&self) -> Result<Option<Vec<u8>>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(&self.path)?))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncState {
+    RemoteEmpty,
+    InSync,
+    LocalAhead { local: u64, remote: u64 },
+    RemoteAhead { local: u64, remote: u64 },
+    Diverged { revision: u64 },
+}
+
+#[derive(Deserialize)]
+struct RevisionHeader {
+    #[serde(default)]
+    revision: u64,
+}
+
+pub fn compare(local: &[u8], remote: Option<&[u8]>) -> Result<SyncState> {
+    let Some(remote) = remote else {
+        return Ok(SyncState::RemoteEmpty);
+    };
+
+    let local_revision = revision_of(local)?;
+    let remote_revision = revision_of(remote)?;
+    Ok(if local_revision > remote_revision {
+        SyncState::LocalAhead {
+            local: local_revision,
+            remote: remote_revision,
+        }
+    } else if remote_revision > local_revision {
+        SyncState::RemoteAhead {
+            local: local_revision,
+            remote: remote_revision,
+        }
+    } else if same_contents(local, remote) {
+        SyncState::InSync
+    } else {
+        SyncState::Diverged {
+            revision: local_revision,
+        }
+    })
+}
+
+fn same_contents(local: &[u8], remote: &[u8]) -> bool {
+    let local = serde_json::from_slice::<serde_json::Value>(local);
+    let remote = serde_json::from_slice::<serde_json::Value>(remote);
+    matches!((local, remote), (Ok(local), Ok(remote)) if local == remote)
+}
+
+fn revision_of(blob: &[u8]) -> Result<u64> {
+    let header: RevisionHeader =
+        serde_json::from_slice(blob).map_err(|e| anyhow!("Not a valid srs store: {e}"))?;
+    Ok(header.revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn blob(revision: u64, marker: &str) -> Vec<u8> {
+        format!(r#"{{"tokens":{{"{marker}":"x"}},"revision":{revision}}}"#).into_bytes()
+    }
+
+    #[test]
+    fn fs_backend_round_trip() {
+        let path = std::env::temp_dir().join(format!("srs_sync_{}.json", Uuid::new_v4()));
+        let backend = FsSyncBackend::new(path.clone());
+        assert!(backend.pull().unwrap().is_none());
+
+        backend.push(b"payload").unwrap();
+        assert_eq!(backend.pull().unwrap().unwrap(), b"payload");
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn compare_uses_revisions() {
+        let local = blob(3, "a");
+        assert_eq!(compare(&local, None).unwrap(), SyncState::RemoteEmpty);
+        assert_eq!(compare(&local, Some(&local)).unwrap(), SyncState::InSync);
+        let reformatted = br#"{ "revision": 3, "tokens": { "a": "x" } }"#;
+        assert_eq!(
+            compare(&local, Some(reformatted)).unwrap(),
+            SyncState::InSync
+        );
+        assert_eq!(
+            compare(&local, Some(&blob(2, "a"))).unwrap(),
+            SyncState::LocalAhead {
+                local: 3,
+                remote: 2
+            }
+        );
+        assert_eq!(
+            compare(&local, Some(&blob(5, "a"))).unwrap(),
+            SyncState::RemoteAhead {
+                local: 3,
+                remote: 5
+            }
+        );
+        assert_eq!(
+            compare(&local, Some(&blob(3, "b"))).unwrap(),
+            SyncState::Diverged { revision: 3 }
+        );
+    }
+
+    #[test]
+    fn compare_treats_unversioned_stores_as_revision_zero() {
+        let legacy = br#"{"tokens":{}}"#.to_vec();
+        assert_eq!(
+            compare(&blob(1, "a"), Some(&legacy)).unwrap(),
+            SyncState::LocalAhead {
+                local: 1,
+                remote: 0
+            }
+        );
+        assert!(compare(&blob(1, "a"), Some(b"garbage")).is_err());
+    }
+}
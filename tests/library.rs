@@ -0,0 +1,110 @@
+use srs::{AddOptions, CryptoManager, StorageError, TokenStorage};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+fn store_path() -> PathBuf {
+    std::env::temp_dir().join(format!("srs_library_test_{}.json", Uuid::new_v4()))
+}
+
+#[test]
+fn drives_a_store_through_the_library_api() {
+    let path = store_path();
+
+    let mut storage = TokenStorage::open(&path, "correct horse").unwrap();
+    storage
+        .add_token("github_token", "ghp_secret", &AddOptions::default())
+        .unwrap();
+    storage
+        .add_token("npm_token", "npm_secret", &AddOptions::default())
+        .unwrap();
+    storage.add_alias("GH", "github_token").unwrap();
+
+    let error = storage
+        .add_token("github_token", "other", &AddOptions::default())
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&StorageError::AlreadyExists("github_token".to_string()))
+    );
+
+    let reopened = TokenStorage::open(&path, "correct horse").unwrap();
+    assert_eq!(
+        reopened.get_token("GH").unwrap().as_deref(),
+        Some("ghp_secret")
+    );
+    let mut names = reopened.list_tokens().unwrap();
+    names.sort();
+    assert_eq!(names, ["github_token", "npm_token"]);
+
+    let error = TokenStorage::open(&path, "wrong").err().unwrap();
+    assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
+
+    let mut storage = reopened;
+    storage.delete_token("npm_token").unwrap();
+    let error = storage.delete_token("npm_token").unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&StorageError::NotFound("npm_token".to_string()))
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn opens_a_store_with_an_injected_key() {
+    let path = store_path();
+
+    let mut storage =
+        TokenStorage::with_crypto_manager(&path, CryptoManager::from_key([7; 32])).unwrap();
+    storage
+        .add_token("foo", "bar", &AddOptions::default())
+        .unwrap();
+
+    let reopened =
+        TokenStorage::with_crypto_manager(&path, CryptoManager::from_key([7; 32])).unwrap();
+    assert_eq!(reopened.get_token("foo").unwrap().as_deref(), Some("bar"));
+
+    let error = TokenStorage::with_crypto_manager(&path, CryptoManager::from_key([8; 32]))
+        .err()
+        .unwrap();
+    assert_eq!(error.downcast_ref(), Some(&StorageError::WrongMasterKey));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn keeps_a_store_in_memory() {
+    let mut storage = TokenStorage::in_memory(CryptoManager::from_key([7; 32]));
+    storage
+        .add_token("github_token", "ghp_secret", &AddOptions::default())
+        .unwrap();
+    storage
+        .add_bytes("signing_key", &[0, 159, 146, 150], &AddOptions::default())
+        .unwrap();
+    storage.add_alias("GH", "github_token").unwrap();
+    assert_eq!(
+        storage.get_token("GH").unwrap().as_deref(),
+        Some("ghp_secret")
+    );
+    assert_eq!(
+        storage.get_bytes("signing_key").unwrap().as_deref(),
+        Some(&[0, 159, 146, 150][..])
+    );
+
+    storage
+        .update_token("github_token", "ghp_rotated", None)
+        .unwrap();
+    assert_eq!(
+        storage.get_token("GH").unwrap().as_deref(),
+        Some("ghp_rotated")
+    );
+
+    storage.delete_token("github_token").unwrap();
+    assert_eq!(storage.get_token("GH").unwrap(), None);
+    let error = storage.delete_token("github_token").unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&StorageError::NotFound("github_token".to_string()))
+    );
+    assert_eq!(storage.list_tokens().unwrap(), ["signing_key"]);
+}